@@ -7,10 +7,14 @@ pub enum Op {
     True,
     False,
     Pop,
+    Dup,
+    Dup2,
+    Bury(usize),
     GetLocal(usize),
     SetLocal(usize),
     GetGlobal(usize),
     DefineGlobal(usize),
+    DefineConstant(usize),
     SetGlobal(usize),
     Equal,
     Greater,
@@ -19,12 +23,32 @@ pub enum Op {
     Subtract,
     Multiply,
     Divide,
+    Modulo,
+    Power,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    ShiftLeft,
+    ShiftRight,
     Not,
     Negate,
     Print,
     JumpIfFalse(usize),
+    JumpIfNotNil(usize),
     Jump(usize),
     Loop(usize),
+    Call(usize),
+    BuildList(usize),
+    BuildMap(usize),
+    IterInit,
+    IterNext(usize),
+    IndexGet,
+    IndexSet,
+    Throw,
+    EndFinally,
+    Import(usize),
+    ImportName(usize),
     Return,
 }
 
@@ -45,10 +69,21 @@ pub fn line(value: usize) -> Line {
 }
 
 
+// Errors raised between `start` and `end` resume at `target` with the value
+// stack cut back to `stack_depth` and the error pushed on top.
+#[derive(Debug, Clone)]
+pub struct Handler {
+    pub start: usize,
+    pub end: usize,
+    pub target: usize,
+    pub stack_depth: usize,
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct Chunk {
     pub code: Vec<(Op, Line)>,
     pub constants: Vec<Value>,
+    pub handlers: Vec<Handler>,
 }
 
 impl Chunk {
@@ -56,9 +91,15 @@ impl Chunk {
         Chunk {
             code: Vec::new(),
             constants: Vec::new(),
+            handlers: Vec::new(),
         }
     }
 
+    // Handlers are recorded innermost first, so the first match wins.
+    pub fn handler_at(&self, offset: usize) -> Option<&Handler> {
+        self.handlers.iter().find(|handler| handler.start <= offset && offset < handler.end)
+    }
+
     pub fn add_constant(&mut self, val: Value) -> usize {
         self.constants.push(val);
         self.constants.len() - 1
@@ -1,4 +1,4 @@
-use std::io::Write;
+use std::collections::HashSet;
 
 use crate::value::{
     Value,
@@ -9,36 +9,50 @@ use crate::scanner::{
     Token,
     TokenType,
 };
-use crate::chunk::{Chunk, Line, Op, line};
+use crate::chunk::{Chunk, Handler, Line, Op, line};
 use crate::object::{Heap, HeapData, ObjFunction};
 
 #[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub enum Precedence {
     None = 0,
     Assignment = 1,
-    Or = 2,
-    And = 3,
-    Equality = 4,
-    Comparison = 5,
-    Term = 6,
-    Factor = 7,
-    Unary = 8,
-    Call = 9,
-    Primary = 10,
+    Conditional = 2,
+    Coalesce = 3,
+    Or = 4,
+    And = 5,
+    Equality = 6,
+    Comparison = 7,
+    BitOr = 8,
+    BitXor = 9,
+    BitAnd = 10,
+    Shift = 11,
+    Term = 12,
+    Factor = 13,
+    Unary = 14,
+    Exponent = 15,
+    Call = 16,
+    Primary = 17,
 }
 
 impl Precedence {
     fn increase(&self) -> Precedence {
         match self {
             Precedence::None => Precedence::Assignment,
-            Precedence::Assignment => Precedence::Or,
+            Precedence::Assignment => Precedence::Conditional,
+            Precedence::Conditional => Precedence::Coalesce,
+            Precedence::Coalesce => Precedence::Or,
             Precedence::Or => Precedence::And,
             Precedence::And => Precedence::Equality,
             Precedence::Equality => Precedence::Comparison,
-            Precedence::Comparison => Precedence::Term,
+            Precedence::Comparison => Precedence::BitOr,
+            Precedence::BitOr => Precedence::BitXor,
+            Precedence::BitXor => Precedence::BitAnd,
+            Precedence::BitAnd => Precedence::Shift,
+            Precedence::Shift => Precedence::Term,
             Precedence::Term => Precedence::Factor,
             Precedence::Factor => Precedence::Unary,
-            Precedence::Unary => Precedence::Call,
+            Precedence::Unary => Precedence::Exponent,
+            Precedence::Exponent => Precedence::Call,
             Precedence::Call => Precedence::Primary,
             Precedence::Primary => Precedence::Primary,
         }
@@ -49,18 +63,43 @@ impl Precedence {
 pub struct Local {
     token: Token,
     depth: isize,
+    constant: bool,
 }
 
+pub struct Loop {
+    start: usize,
+    scope_depth: isize,
+    break_jumps: Vec<usize>,
+}
+
+#[allow(dead_code)]
 pub enum FunctionType {
     Function,
     Script,
 }
 
+#[derive(Clone, Copy)]
+pub enum LoopExit {
+    Break,
+    Continue,
+}
+
+// A `break` or `continue` that leaves a try statement is compiled as a jump to the end of the
+// statement, where it is reissued once any finally block has run.
+pub struct TryBlock {
+    scope_depth: isize,
+    loop_count: usize,
+    exits: Vec<(usize, LoopExit)>,
+}
+
 pub struct Compiler {
     function: ObjFunction,
+    #[allow(dead_code)]
     function_type: FunctionType,
     locals: Vec<Local>,
     scope_depth: isize,
+    loops: Vec<Loop>,
+    tries: Vec<TryBlock>,
 }
 
 impl Compiler {
@@ -70,15 +109,19 @@ impl Compiler {
             function_type: FunctionType::Function,
             locals: Vec::new(),
             scope_depth: 0,
+            loops: Vec::new(),
+            tries: Vec::new(),
         }
     }
     
 }
 
+pub type ParseFn = fn(&mut Parser, &str, &mut Chunk, &mut scanner::Scanner, &mut Heap, bool);
+
 #[derive(Clone)]
 pub struct ParseRule {
-    pub prefix: Option<fn(&mut Parser, &String, &mut Chunk, &mut scanner::Scanner, &mut Heap, bool)>,
-    pub infix: Option<fn(&mut Parser, &String, &mut Chunk, &mut scanner::Scanner, &mut Heap, bool)>,
+    pub prefix: Option<ParseFn>,
+    pub infix: Option<ParseFn>,
     pub precedence: Precedence,
 }
 
@@ -87,6 +130,9 @@ pub struct Parser<> {
     previous: scanner::Token,
     had_error: bool,
     panic_mode: bool,
+    prefix_increment: Option<Op>,
+    // Globals declared `const` earlier in this source, so assigning them fails at compile time.
+    constant_globals: HashSet<String>,
     compiler: Compiler,
 }
 
@@ -94,19 +140,21 @@ impl Parser {
     pub fn new() -> Parser {
         Parser {
             current: Token {
-                token_type: TokenType::EOF,
+                token_type: TokenType::Eof,
                 start: 0,
                 length: 0,
                 line: 0,
             },
             previous: Token {
-                token_type: TokenType::EOF,
+                token_type: TokenType::Eof,
                 start: 0,
                 length: 0,
                 line: 0,
             },
             had_error: false,
             panic_mode: false,
+            prefix_increment: None,
+            constant_globals: HashSet::new(),
             compiler: Compiler::new(),
         }
     }
@@ -117,18 +165,29 @@ impl Parser {
 
         self.compiler.function.set_chunk(chunk.clone());
         
-        while !self.match_token(TokenType::EOF, &source, &mut scanner) {
+        while !self.match_token(TokenType::Eof, &source, &mut scanner) {
             self.declaration(&source, chunk, &mut scanner, heap);
         }
 
         !self.had_error
     }
 
-    pub fn advance(&mut self, source: &String, scanner: &mut scanner::Scanner) {
+    pub fn compile_expression(&mut self, source: String, chunk: &mut Chunk, heap: &mut Heap) -> bool {
+        let mut scanner = scanner::Scanner::new();
+        self.advance(&source, &mut scanner);
+
+        self.expression(&source, chunk, &mut scanner, heap, false);
+        self.consume(&source, TokenType::Eof, "Expect end of expression.", &mut scanner);
+        self.emit_byte(chunk, (Op::Return, line(self.previous.line)));
+
+        !self.had_error
+    }
+
+    pub fn advance(&mut self, source: &str, scanner: &mut scanner::Scanner) {
         self.previous = self.current.clone();
 
         loop {
-            self.current = scanner.scan_token(&source);
+            self.current = scanner.scan_token(source);
             
             if self.current.token_type != TokenType::Error {
                 break;
@@ -148,35 +207,68 @@ impl Parser {
 
     pub fn error_at(&mut self, token: &Token, message: &str) {
         self.panic_mode = true;
-        write!(std::io::stderr(), "[line {}] Error", token.line).unwrap();
+        eprint!("[line {}] Error", token.line);
 
-        if token.token_type == TokenType::EOF {
-            write!(std::io::stderr(), " at end").unwrap();
+        if token.token_type == TokenType::Eof {
+            eprint!(" at end");
         } else if token.token_type == TokenType::Error {
             // Nothing.
         } else {
-            write!(std::io::stderr(), " at '{:.*}'", token.length, token.start).unwrap();
+            eprint!(" at '{:.*}'", token.length, token.start);
         }
 
-        writeln!(std::io::stderr(), ": {}", message).unwrap();
+        eprintln!(": {}", message);
         self.had_error = true;
     }
 
     pub fn add_local(&mut self, token: Token) {
         self.compiler.locals.push(Local {
-            token: token,
+            token,
             depth: -1,
+            constant: false,
+        });
+    }
+
+    pub fn add_hidden_local(&mut self) {
+        let token = Token {
+            token_type: TokenType::Identifier,
+            start: 0,
+            length: 0,
+            line: self.previous.line,
+        };
+        self.compiler.locals.push(Local {
+            token,
+            depth: self.compiler.scope_depth,
+            constant: false,
         });
     }
 
-    pub fn block(&mut self, source: &String, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
-        while !(self.current.token_type == TokenType::RightBrace) && !(self.current.token_type == TokenType::EOF) {
+    pub fn block(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
+        while !(self.current.token_type == TokenType::RightBrace) && !(self.current.token_type == TokenType::Eof) {
             self.declaration(source, chunk, scanner, heap);
         }
 
         self.consume(source, TokenType::RightBrace, "Expect '}' after block.", scanner);
     }
 
+    pub fn argument_list(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) -> usize {
+        let mut arg_count = 0;
+        if self.current.token_type != TokenType::RightParen {
+            loop {
+                self.expression(source, chunk, scanner, heap, false);
+                if arg_count == 255 {
+                    self.error_at_previous("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+                if !self.match_token(TokenType::Comma, source, scanner) {
+                    break;
+                }
+            }
+        }
+        self.consume(source, TokenType::RightParen, "Expect ')' after arguments.", scanner);
+        arg_count
+    }
+
     pub fn begin_scope(&mut self) {
         self.compiler.scope_depth += 1;
     }
@@ -184,15 +276,75 @@ impl Parser {
     pub fn end_scope(&mut self, chunk: &mut Chunk) {
         self.compiler.scope_depth -= 1;
 
-        let locals_to_pop: usize = self.compiler.locals.iter().rev()
-            .take_while(|local| local.depth > self.compiler.scope_depth).collect::<Vec<_>>().len();
+        let locals_to_pop = self.pop_locals(chunk, self.compiler.scope_depth);
+        let remaining = self.compiler.locals.len() - locals_to_pop;
+        self.compiler.locals.truncate(remaining);
+    }
+
+    pub fn pop_locals(&mut self, chunk: &mut Chunk, depth: isize) -> usize {
+        let locals_to_pop = self.compiler.locals.iter().rev()
+            .take_while(|local| local.depth > depth).count();
 
         for _ in 0..locals_to_pop {
             self.emit_byte(chunk, (Op::Pop, line(self.previous.line)));
         }
+        locals_to_pop
+    }
+
+    pub fn begin_loop(&mut self, start: usize) {
+        self.compiler.loops.push(Loop {
+            start,
+            scope_depth: self.compiler.scope_depth,
+            break_jumps: Vec::new(),
+        });
     }
 
-    pub fn consume(&mut self, source: &String, token_type: TokenType, message: &str, scanner: &mut scanner::Scanner) {
+    pub fn end_loop(&mut self, chunk: &mut Chunk) {
+        let current_loop = self.compiler.loops.pop().unwrap();
+        for break_jump in current_loop.break_jumps {
+            self.patch_jump(chunk, break_jump);
+        }
+    }
+
+    pub fn break_statement(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner) {
+        self.consume(source, TokenType::Semicolon, "Expect ';' after 'break'.", scanner);
+        if self.compiler.loops.is_empty() {
+            self.error_at_previous("Can't use 'break' outside of a loop.");
+            return;
+        }
+        self.emit_loop_exit(chunk, LoopExit::Break);
+    }
+
+    pub fn continue_statement(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner) {
+        self.consume(source, TokenType::Semicolon, "Expect ';' after 'continue'.", scanner);
+        if self.compiler.loops.is_empty() {
+            self.error_at_previous("Can't use 'continue' outside of a loop.");
+            return;
+        }
+        self.emit_loop_exit(chunk, LoopExit::Continue);
+    }
+
+    fn emit_loop_exit(&mut self, chunk: &mut Chunk, exit: LoopExit) {
+        let loop_count = self.compiler.loops.len();
+        if let Some(try_block) = self.compiler.tries.last().filter(|try_block| try_block.loop_count == loop_count) {
+            self.pop_locals(chunk, try_block.scope_depth);
+            let jump = self.emit_jump(chunk, Op::Jump(0));
+            self.compiler.tries.last_mut().unwrap().exits.push((jump, exit));
+            return;
+        }
+        let current_loop = self.compiler.loops.last().unwrap();
+        let (start, scope_depth) = (current_loop.start, current_loop.scope_depth);
+        self.pop_locals(chunk, scope_depth);
+        match exit {
+            LoopExit::Break => {
+                let jump = self.emit_jump(chunk, Op::Jump(0));
+                self.compiler.loops.last_mut().unwrap().break_jumps.push(jump);
+            },
+            LoopExit::Continue => self.emit_loop(chunk, start),
+        }
+    }
+
+    pub fn consume(&mut self, source: &str, token_type: TokenType, message: &str, scanner: &mut scanner::Scanner) {
         if self.current.token_type == token_type {
             self.advance(source, scanner);
             return;
@@ -201,9 +353,15 @@ impl Parser {
         self.error_at_current(message);
     }
 
-    pub fn declaration(&mut self, source: &String, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
+    pub fn declaration(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
         if self.match_token(TokenType::Var, source, scanner) {
             self.var_declaration(source, chunk, scanner, heap);
+        } else if self.match_token(TokenType::Const, source, scanner) {
+            self.const_declaration(source, chunk, scanner, heap);
+        } else if self.match_token(TokenType::Import, source, scanner) {
+            self.import_declaration(source, chunk, scanner, heap);
+        } else if self.match_token(TokenType::From, source, scanner) {
+            self.import_from_declaration(source, chunk, scanner, heap);
         } else {
             self.statement(source, chunk, scanner, heap);
         } 
@@ -213,7 +371,7 @@ impl Parser {
         }
     }
 
-    pub fn declare_variable(&mut self, source: &String) {
+    pub fn declare_variable(&mut self, source: &str) {
         if self.compiler.scope_depth == 0 {
             return;
         }
@@ -283,13 +441,21 @@ impl Parser {
         self.emit_byte(chunk, (Op::Loop(offset), line(self.previous.line)));
     }
 
-    pub fn for_statement(&mut self, source: &String, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
+    pub fn for_statement(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
         self.begin_scope();
         self.consume(source, TokenType::LeftParen, "Expect '(' after 'for'.", scanner);
         if self.match_token(TokenType::Semicolon, source, scanner) {
             // No initializer.
         } else if self.match_token(TokenType::Var, source, scanner) {
-            self.var_declaration(source, chunk, scanner, heap);
+            self.consume(source, TokenType::Identifier, "Expect variable name.", scanner);
+            let name = self.previous.clone();
+            if self.match_token(TokenType::In, source, scanner) {
+                self.for_in_statement(source, chunk, scanner, heap, name);
+                self.end_scope(chunk);
+                return;
+            }
+            self.declare_variable(source);
+            self.var_initializer(source, chunk, scanner, heap, 0);
         } else {
             self.expression(source, chunk, scanner, heap, false);
             self.consume(source, TokenType::Semicolon, "Expect ';' after loop initializer.", scanner);
@@ -316,6 +482,7 @@ impl Parser {
             self.patch_jump(chunk, body_jump);
         }
 
+        self.begin_loop(loop_start);
         self.statement(source, chunk, scanner, heap);
         self.emit_loop(chunk, loop_start);
 
@@ -323,16 +490,151 @@ impl Parser {
             self.patch_jump(chunk, exit_jump);
             self.emit_byte(chunk, (Op::Pop, line(self.previous.line)));
         }
+        self.end_loop(chunk);
+
+        self.end_scope(chunk);
+    }
+
+    pub fn for_in_statement(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, name: Token) {
+        self.expression(source, chunk, scanner, heap, false);
+        self.consume(source, TokenType::RightParen, "Expect ')' after for-in expression.", scanner);
+
+        // The iterable and the iterator's position live in unnamed locals below the loop variable.
+        self.emit_byte(chunk, (Op::IterInit, line(self.previous.line)));
+        let iterable_slot = self.compiler.locals.len();
+        self.add_hidden_local();
+        self.add_hidden_local();
+        self.emit_byte(chunk, (Op::Nil, line(self.previous.line)));
+        let variable_slot = self.compiler.locals.len();
+        self.add_local(name);
+        self.mark_initialized();
+
+        let loop_start = chunk.code.len();
+        self.emit_byte(chunk, (Op::IterNext(iterable_slot), line(self.previous.line)));
+        let exit_jump = self.emit_jump(chunk, Op::JumpIfFalse(0));
+        self.emit_byte(chunk, (Op::Pop, line(self.previous.line)));
+        self.emit_bytes(chunk, (Op::SetLocal(variable_slot), line(self.previous.line)), (Op::Pop, line(self.previous.line)));
+
+        self.begin_loop(loop_start);
+        self.statement(source, chunk, scanner, heap);
+        self.emit_loop(chunk, loop_start);
+
+        self.patch_jump(chunk, exit_jump);
+        self.emit_byte(chunk, (Op::Pop, line(self.previous.line)));
+        self.end_loop(chunk);
+    }
+
+    pub fn throw_statement(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
+        self.expression(source, chunk, scanner, heap, false);
+        self.consume(source, TokenType::Semicolon, "Expect ';' after thrown value.", scanner);
+        self.emit_byte(chunk, (Op::Throw, line(self.previous.line)));
+    }
+
+    pub fn try_statement(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
+        // Statements leave nothing behind on the stack, so the locals are all a handler has to keep.
+        let stack_depth = self.compiler.locals.len();
+        let try_start = chunk.code.len();
+        self.compiler.tries.push(TryBlock {
+            scope_depth: self.compiler.scope_depth,
+            loop_count: self.compiler.loops.len(),
+            exits: Vec::new(),
+        });
+        self.consume(source, TokenType::LeftBrace, "Expect '{' after 'try'.", scanner);
+        self.begin_scope();
+        self.block(source, chunk, scanner, heap);
+        self.end_scope(chunk);
+        let try_end = chunk.code.len();
+        let try_exit = self.emit_jump(chunk, Op::Jump(0));
+
+        let has_catch = self.match_token(TokenType::Catch, source, scanner);
+        if has_catch {
+            chunk.handlers.push(Handler { start: try_start, end: try_end, target: chunk.code.len(), stack_depth });
+            self.consume(source, TokenType::LeftParen, "Expect '(' after 'catch'.", scanner);
+            self.consume(source, TokenType::Identifier, "Expect error variable name.", scanner);
+            self.begin_scope();
+            self.declare_variable(source);
+            self.mark_initialized();
+            self.consume(source, TokenType::RightParen, "Expect ')' after error variable.", scanner);
+            self.consume(source, TokenType::LeftBrace, "Expect '{' before catch body.", scanner);
+            self.block(source, chunk, scanner, heap);
+            self.end_scope(chunk);
+        }
+        self.patch_jump(chunk, try_exit);
+        let exits = self.compiler.tries.pop().unwrap().exits;
+
+        if !self.match_token(TokenType::Finally, source, scanner) {
+            if !has_catch {
+                self.error_at_current("Expect 'catch' or 'finally' after try block.");
+            }
+            let end_jump = self.emit_jump(chunk, Op::Jump(0));
+            for (jump, exit) in exits {
+                self.patch_jump(chunk, jump);
+                self.emit_loop_exit(chunk, exit);
+            }
+            self.patch_jump(chunk, end_jump);
+            return;
+        }
+        // The finally body runs with two hidden locals: a pending value and what to do with it
+        // afterwards. False means carry on, true means rethrow the value, and a number is the
+        // offset of a reissued break or continue.
+        let protected_end = chunk.code.len();
+        self.emit_bytes(chunk, (Op::Nil, line(self.previous.line)), (Op::False, line(self.previous.line)));
+        let mut body_jumps = vec![self.emit_jump(chunk, Op::Jump(0))];
+        let mut exit_targets = Vec::new();
+        for (jump, _) in exits.iter() {
+            self.patch_jump(chunk, *jump);
+            let target = chunk.add_constant(Number(0.0));
+            self.emit_bytes(chunk, (Op::Nil, line(self.previous.line)), (Op::Constant(target), line(self.previous.line)));
+            body_jumps.push(self.emit_jump(chunk, Op::Jump(0)));
+            exit_targets.push(target);
+        }
+        chunk.handlers.push(Handler { start: try_start, end: protected_end, target: chunk.code.len(), stack_depth });
+        self.emit_byte(chunk, (Op::True, line(self.previous.line)));
+        for body_jump in body_jumps {
+            self.patch_jump(chunk, body_jump);
+        }
 
+        self.begin_scope();
+        self.add_hidden_local();
+        self.add_hidden_local();
+        self.consume(source, TokenType::LeftBrace, "Expect '{' after 'finally'.", scanner);
+        self.begin_scope();
+        self.block(source, chunk, scanner, heap);
         self.end_scope(chunk);
+        // EndFinally pops both hidden locals itself, then rethrows or jumps as they say.
+        self.emit_byte(chunk, (Op::EndFinally, line(self.previous.line)));
+        self.compiler.scope_depth -= 1;
+        self.compiler.locals.truncate(stack_depth);
+
+        if exits.is_empty() {
+            return;
+        }
+        let end_jump = self.emit_jump(chunk, Op::Jump(0));
+        for ((_, exit), target) in exits.into_iter().zip(exit_targets) {
+            chunk.constants[target] = Number(chunk.code.len() as f64);
+            self.emit_loop_exit(chunk, exit);
+        }
+        self.patch_jump(chunk, end_jump);
     }
 
-    pub fn identifier_constant(&mut self, source: &String, chunk: &mut Chunk, heap: &mut Heap) -> usize {
+    pub fn identifier_constant(&mut self, source: &str, chunk: &mut Chunk, heap: &mut Heap) -> usize {
         let identifier = source.chars().skip(self.previous.start).take(self.previous.length).collect::<String>();
-        chunk.add_constant(Value::Obj(heap.allocate(HeapData::String(identifier))))
+        let identifier = self.allocate_string(heap, identifier);
+        chunk.add_constant(identifier)
     }
 
-    pub fn if_statement(&mut self, source: &String, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
+    // Constants count against the heap's memory cap like everything else the program allocates.
+    fn allocate_string(&mut self, heap: &mut Heap, string: String) -> Value {
+        match heap.try_allocate(HeapData::String(string)) {
+            Ok(heap_id) => Value::Obj(heap_id),
+            Err(message) => {
+                self.error_at_previous(&message);
+                Value::Nil
+            },
+        }
+    }
+
+    pub fn if_statement(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
         self.consume(source, TokenType::LeftParen, "Expect '(' after 'if'.", scanner);
         self.expression(source, chunk, scanner, heap, false);
         self.consume(source, TokenType::RightParen, "Expect ')' after condition.", scanner);
@@ -354,7 +656,7 @@ impl Parser {
         self.patch_jump(chunk, else_jump);
     }
 
-    pub fn match_token(&mut self, token_type: TokenType, source: &String, scanner: &mut scanner::Scanner, ) -> bool {
+    pub fn match_token(&mut self, token_type: TokenType, source: &str, scanner: &mut scanner::Scanner, ) -> bool {
         if self.current.token_type != token_type {
             return false;
         }
@@ -369,26 +671,81 @@ impl Parser {
         self.compiler.locals.last_mut().unwrap().depth = self.compiler.scope_depth;
     }
 
-    pub fn named_variable(&mut self, source: &String, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, can_assign: bool) {
+    pub fn named_variable(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, can_assign: bool) {
         let global = self.identifier_constant(source, chunk, heap);
         let local = self.resolve_local(source);
+        let name = self.previous_text(source);
+        let (get_op, set_op, constant) = if local != -1 {
+            (Op::GetLocal(local as usize), Op::SetLocal(local as usize), self.compiler.locals[local as usize].constant)
+        } else {
+            (Op::GetGlobal(global), Op::SetGlobal(global), self.constant_globals.contains(&name))
+        };
+        let reject_constant = |parser: &mut Parser| {
+            if constant {
+                parser.error_at_previous(&format!("Can't assign to constant '{}'.", name));
+            }
+        };
+
         if can_assign && self.match_token(TokenType::Equal, source, scanner) {
+            reject_constant(self);
             self.expression(source, chunk, scanner, heap, can_assign);
-            if local != -1 {
-                self.emit_byte(chunk, (Op::SetLocal(local as usize), line(self.previous.line)));
-            } else {
-                self.emit_byte(chunk, (Op::SetGlobal(global), line(self.previous.line)));
-            }
+            self.emit_byte(chunk, (set_op, line(self.previous.line)));
+        } else if let Some(op) = self.match_compound_assignment(source, scanner, can_assign) {
+            reject_constant(self);
+            self.emit_byte(chunk, (get_op, line(self.previous.line)));
+            self.expression(source, chunk, scanner, heap, false);
+            self.emit_bytes(chunk, (op, line(self.previous.line)), (set_op, line(self.previous.line)));
+        } else if let Some(op) = self.take_prefix_increment() {
+            reject_constant(self);
+            self.emit_byte(chunk, (get_op, line(self.previous.line)));
+            self.emit_constant(chunk, Number(1.0), line(self.previous.line));
+            self.emit_bytes(chunk, (op, line(self.previous.line)), (set_op, line(self.previous.line)));
+        } else if let Some(op) = self.match_postfix_increment(source, scanner) {
+            reject_constant(self);
+            self.emit_bytes(chunk, (get_op.clone(), line(self.previous.line)), (get_op, line(self.previous.line)));
+            self.emit_constant(chunk, Number(1.0), line(self.previous.line));
+            self.emit_bytes(chunk, (op, line(self.previous.line)), (set_op, line(self.previous.line)));
+            self.emit_byte(chunk, (Op::Pop, line(self.previous.line)));
         } else {
-            if local != -1 {
-                self.emit_byte(chunk, (Op::GetLocal(local as usize), line(self.previous.line)));
-            } else {
-                self.emit_byte(chunk, (Op::GetGlobal(global), line(self.previous.line)));
-            }
+            self.emit_byte(chunk, (get_op, line(self.previous.line)));
+        }
+    }
+
+    pub fn match_compound_assignment(&mut self, source: &str, scanner: &mut scanner::Scanner, can_assign: bool) -> Option<Op> {
+        if !can_assign {
+            return None;
+        }
+        let op = match self.current.token_type {
+            TokenType::PlusEqual => Op::Add,
+            TokenType::MinusEqual => Op::Subtract,
+            TokenType::StarEqual => Op::Multiply,
+            TokenType::SlashEqual => Op::Divide,
+            TokenType::PercentEqual => Op::Modulo,
+            _ => return None,
+        };
+        self.advance(source, scanner);
+        Some(op)
+    }
+
+    pub fn match_postfix_increment(&mut self, source: &str, scanner: &mut scanner::Scanner) -> Option<Op> {
+        if self.match_token(TokenType::PlusPlus, source, scanner) {
+            Some(Op::Add)
+        } else if self.match_token(TokenType::MinusMinus, source, scanner) {
+            Some(Op::Subtract)
+        } else {
+            None
         }
     }
 
-    pub fn parse_variable(&mut self, source: &String, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) -> usize {
+    pub fn take_prefix_increment(&mut self) -> Option<Op> {
+        // A prefix ++ applies to the end of the operand chain, so `++xs[i]` increments the element, not xs.
+        match self.current.token_type {
+            TokenType::LeftBracket | TokenType::LeftParen => None,
+            _ => self.prefix_increment.take(),
+        }
+    }
+
+    pub fn parse_variable(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) -> usize {
         self.consume(source, TokenType::Identifier, "Expect variable name.", scanner);
 
         self.declare_variable(source);
@@ -406,18 +763,20 @@ impl Parser {
             chunk.code[offset] = (Op::JumpIfFalse(jump), lineno.clone());
         } else if let Op::Jump(_) = maybe_jump {
             chunk.code[offset] = (Op::Jump(jump), lineno.clone());
+        } else if let Op::JumpIfNotNil(_) = maybe_jump {
+            chunk.code[offset] = (Op::JumpIfNotNil(jump), lineno.clone());
         } else {
             self.error_at_previous("Cannot jump here.");
         }
     }
 
-    pub fn print_statement(&mut self, source: &String, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
+    pub fn print_statement(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
         self.expression(source, chunk, scanner, heap, false);
         self.consume(source, TokenType::Semicolon, "Expect ';' after value.", scanner);
         self.emit_byte(chunk, (Op::Print, line(self.previous.line)));
     }
 
-    pub fn resolve_local(&mut self, source: &String) -> isize {
+    pub fn resolve_local(&mut self, source: &str) -> isize {
         let name = source.chars().skip(self.previous.start).take(self.previous.length).collect::<String>();
         let locals = self.compiler.locals.clone();
         for (i, local) in locals.iter().enumerate().rev() {
@@ -433,7 +792,7 @@ impl Parser {
         -1
     }
 
-    pub fn statement(&mut self, source: &String, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
+    pub fn statement(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
         if self.match_token(TokenType::Print, source, scanner) {
             self.print_statement(source, chunk, scanner, heap);
         } else if self.match_token(TokenType::For, source, scanner) {
@@ -442,6 +801,14 @@ impl Parser {
             self.if_statement(source, chunk, scanner, heap);
         } else if self.match_token(TokenType::While, source, scanner) {
             self.while_statement(source, chunk, scanner, heap);
+        } else if self.match_token(TokenType::Break, source, scanner) {
+            self.break_statement(source, chunk, scanner);
+        } else if self.match_token(TokenType::Continue, source, scanner) {
+            self.continue_statement(source, chunk, scanner);
+        } else if self.match_token(TokenType::Throw, source, scanner) {
+            self.throw_statement(source, chunk, scanner, heap);
+        } else if self.match_token(TokenType::Try, source, scanner) {
+            self.try_statement(source, chunk, scanner, heap);
         } else if self.match_token(TokenType::LeftBrace, source, scanner) { 
             self.begin_scope();
             self.block(source, chunk, scanner, heap);
@@ -449,22 +816,21 @@ impl Parser {
         } else {
             self.expression(source, chunk, scanner, heap, false);
             self.consume(source, TokenType::Semicolon, "Expect ';' after expression.", scanner);
-
-            // This might be used later.
-            // self.emit_byte(chunk, (Op::Pop, line(self.previous.line)));
+            self.emit_byte(chunk, (Op::Pop, line(self.previous.line)));
         }
     }
 
-    pub fn synchronize(&mut self, source: &String, scanner: &mut scanner::Scanner) {
+    pub fn synchronize(&mut self, source: &str, scanner: &mut scanner::Scanner) {
         self.panic_mode = false;
 
-        while self.current.token_type != TokenType::EOF {
+        while self.current.token_type != TokenType::Eof {
             if self.previous.token_type == TokenType::Semicolon {
                 return;
             }
 
             match self.current.token_type {
-                TokenType::Class | TokenType::Fun | TokenType::Var | TokenType::For | TokenType::If | TokenType::While | TokenType::Print | TokenType::Return => {
+                TokenType::Class | TokenType::Fun | TokenType::Var | TokenType::Const | TokenType::For | TokenType::If | TokenType::While | TokenType::Print | TokenType::Return | TokenType::Throw | TokenType::Try
+                    | TokenType::Import | TokenType::From => {
                     return;
                 },
                 _ => (),
@@ -474,8 +840,71 @@ impl Parser {
         }
     }
 
-    pub fn var_declaration(&mut self, source: &String, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
+    pub fn var_declaration(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
         let global = self.parse_variable(source, chunk, scanner, heap);
+        self.var_initializer(source, chunk, scanner, heap, global);
+    }
+
+    pub fn const_declaration(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
+        let global = self.parse_variable(source, chunk, scanner, heap);
+        let name = self.previous_text(source);
+        self.consume(source, TokenType::Equal, "Expect '=' after constant name.", scanner);
+        self.expression(source, chunk, scanner, heap, false);
+        self.consume(source, TokenType::Semicolon, "Expect ';' after constant declaration.", scanner);
+        self.define_constant(chunk, global, name);
+    }
+
+    fn define_constant(&mut self, chunk: &mut Chunk, global: usize, name: String) {
+        if self.compiler.scope_depth > 0 {
+            self.mark_initialized();
+            self.compiler.locals.last_mut().unwrap().constant = true;
+            return;
+        }
+        self.constant_globals.insert(name);
+        self.emit_byte(chunk, (Op::DefineConstant(global), line(self.previous.line)));
+    }
+
+    pub fn import_declaration(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
+        let path = self.module_path(source, chunk, scanner, heap);
+        // `as` is only special here, so it stays usable as a variable name.
+        self.consume(source, TokenType::Identifier, "Expect 'as' after module path.", scanner);
+        if self.previous_text(source) != "as" {
+            self.error_at_previous("Expect 'as' after module path.");
+        }
+        let global = self.parse_variable(source, chunk, scanner, heap);
+        self.emit_byte(chunk, (Op::Import(path), line(self.previous.line)));
+        self.define_variable(chunk, global);
+        self.consume(source, TokenType::Semicolon, "Expect ';' after import.", scanner);
+    }
+
+    pub fn import_from_declaration(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
+        let path = self.module_path(source, chunk, scanner, heap);
+        self.consume(source, TokenType::Import, "Expect 'import' after module path.", scanner);
+        loop {
+            let global = self.parse_variable(source, chunk, scanner, heap);
+            let name = self.identifier_constant(source, chunk, heap);
+            self.emit_bytes(chunk, (Op::Import(path), line(self.previous.line)), (Op::ImportName(name), line(self.previous.line)));
+            // Imported names are copies of the module's variables, so they're bound read-only.
+            self.define_constant(chunk, global, self.previous_text(source));
+            if !self.match_token(TokenType::Comma, source, scanner) {
+                break;
+            }
+        }
+        self.consume(source, TokenType::Semicolon, "Expect ';' after import.", scanner);
+    }
+
+    fn module_path(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) -> usize {
+        self.consume(source, TokenType::String, "Expect module path string.", scanner);
+        let path = source.chars().skip(self.previous.start + 1).take(self.previous.length.saturating_sub(2)).collect::<String>();
+        let path = self.allocate_string(heap, path);
+        chunk.add_constant(path)
+    }
+
+    fn previous_text(&self, source: &str) -> String {
+        source.chars().skip(self.previous.start).take(self.previous.length).collect()
+    }
+
+    pub fn var_initializer(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, global: usize) {
         if self.match_token(TokenType::Equal, source, scanner) {
             self.expression(source, chunk, scanner, heap, false);
         } else {
@@ -485,7 +914,7 @@ impl Parser {
         self.define_variable(chunk, global);
     }
 
-    pub fn while_statement(&mut self, source: &String, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
+    pub fn while_statement(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap) {
         let loop_start = chunk.code.len();
         self.consume(source, TokenType::LeftParen, "Expect '(' after 'while'.", scanner);
         self.expression(source, chunk, scanner, heap, false);
@@ -493,16 +922,18 @@ impl Parser {
 
         let exit_jump = self.emit_jump(chunk, Op::JumpIfFalse(0));
         self.emit_byte(chunk, (Op::Pop, line(self.previous.line)));
+        self.begin_loop(loop_start);
         self.statement(source, chunk, scanner, heap);
         self.emit_loop(chunk, loop_start);
 
         self.patch_jump(chunk, exit_jump);
         self.emit_byte(chunk, (Op::Pop, line(self.previous.line)));
+        self.end_loop(chunk);
     }
 
     // PREFIXES AND INFIXES ----------------------------------------------------
 
-    pub fn literal(&mut self, _source: &String, chunk: &mut Chunk, _scanner: &mut scanner::Scanner, _heap: &mut Heap, _can_assign: bool) {
+    pub fn literal(&mut self, _source: &str, chunk: &mut Chunk, _scanner: &mut scanner::Scanner, _heap: &mut Heap, _can_assign: bool) {
         match self.previous.token_type {
             TokenType::False => self.emit_byte(chunk, (Op::False, line(self.previous.line))),
             TokenType::Nil => self.emit_byte(chunk, (Op::Nil, line(self.previous.line))),
@@ -511,38 +942,60 @@ impl Parser {
         }
     }
 
-    pub fn string(&mut self, source: &String, chunk: &mut Chunk, _scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
+    pub fn string(&mut self, source: &str, chunk: &mut Chunk, _scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
         let string = source.chars().skip(self.previous.start + 1).take(self.previous.length - 2).collect::<String>();
-        let heap_id = heap.allocate(HeapData::String(string));
-        self.emit_constant(chunk, Obj(heap_id), line(self.previous.line));
+        let string = self.allocate_string(heap, string);
+        self.emit_constant(chunk, string, line(self.previous.line));
     }
 
-    pub fn expression(&mut self, source: &String, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
+    pub fn expression(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
+        let prefix_increment = self.prefix_increment.take();
         self.parse_precedence(source, chunk, Precedence::Assignment, scanner, heap);
+        self.prefix_increment = prefix_increment;
     }
 
-    pub fn variable(&mut self, source: &String, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, can_assign: bool) {
+    pub fn variable(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, can_assign: bool) {
         self.named_variable(source, chunk, scanner, heap, can_assign);
     }
 
-    pub fn number(&mut self, source: &String, chunk: &mut Chunk, _scanner: &mut scanner::Scanner, _heap: &mut Heap, _can_assign: bool) {
+    pub fn number(&mut self, source: &str, chunk: &mut Chunk, _scanner: &mut scanner::Scanner, _heap: &mut Heap, _can_assign: bool) {
         let val = source[self.previous.start..self.previous.start + self.previous.length].parse::<f64>().unwrap();
         self.emit_constant(chunk, Number(val), line(self.previous.line));
     }
 
-    pub fn grouping(&mut self, source: &String, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
+    pub fn grouping(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
         self.expression(source, chunk, scanner, heap, false);
         self.consume(source, TokenType::RightParen, "Expect ')' after expression.", scanner);
     }
 
-    pub fn and(&mut self, source: &String, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
+    pub fn and(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
         let end_jump = self.emit_jump(chunk, Op::JumpIfFalse(0));
         self.emit_byte(chunk, (Op::Pop, line(self.previous.line)));
         self.parse_precedence(source, chunk, Precedence::And, scanner, heap);
         self.patch_jump(chunk, end_jump);
     }
 
-    pub fn or(&mut self, source: &String, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
+    pub fn conditional(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
+        let else_jump = self.emit_jump(chunk, Op::JumpIfFalse(0));
+        self.emit_byte(chunk, (Op::Pop, line(self.previous.line)));
+        self.parse_precedence(source, chunk, Precedence::Conditional, scanner, heap);
+        self.consume(source, TokenType::Colon, "Expect ':' after then branch of conditional expression.", scanner);
+
+        let end_jump = self.emit_jump(chunk, Op::Jump(0));
+        self.patch_jump(chunk, else_jump);
+        self.emit_byte(chunk, (Op::Pop, line(self.previous.line)));
+        self.parse_precedence(source, chunk, Precedence::Conditional, scanner, heap);
+        self.patch_jump(chunk, end_jump);
+    }
+
+    pub fn coalesce(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
+        let end_jump = self.emit_jump(chunk, Op::JumpIfNotNil(0));
+        self.emit_byte(chunk, (Op::Pop, line(self.previous.line)));
+        self.parse_precedence(source, chunk, Precedence::Coalesce, scanner, heap);
+        self.patch_jump(chunk, end_jump);
+    }
+
+    pub fn or(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
         let else_jump = self.emit_jump(chunk, Op::JumpIfFalse(0));
         let end_jump = self.emit_jump(chunk, Op::Jump(0));
         self.patch_jump(chunk, else_jump);
@@ -551,20 +1004,105 @@ impl Parser {
         self.patch_jump(chunk, end_jump);
     }
 
-    pub fn unary(&mut self, source: &String, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
+    pub fn unary(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
         let operator_type = self.previous.clone().token_type;
         self.parse_precedence(source, chunk,  Precedence::Unary, scanner, heap);
         match operator_type {
             TokenType::Bang => self.emit_byte(chunk, (Op::Not, line(self.previous.line))),
             TokenType::Minus => self.emit_byte(chunk, (Op::Negate, line(self.previous.line))),
+            TokenType::Tilde => self.emit_byte(chunk, (Op::BitNot, line(self.previous.line))),
             _ => (),
         }
     }
 
-    pub fn binary(&mut self, source: &String, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
+    pub fn call(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
+        let arg_count = self.argument_list(source, chunk, scanner, heap);
+        self.emit_byte(chunk, (Op::Call(arg_count), line(self.previous.line)));
+    }
+
+    pub fn list(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
+        let mut count = 0;
+        if self.current.token_type != TokenType::RightBracket {
+            loop {
+                self.expression(source, chunk, scanner, heap, false);
+                if count == 255 {
+                    self.error_at_previous("Can't have more than 255 elements in a list literal.");
+                }
+                count += 1;
+                if !self.match_token(TokenType::Comma, source, scanner) {
+                    break;
+                }
+            }
+        }
+        self.consume(source, TokenType::RightBracket, "Expect ']' after list elements.", scanner);
+        self.emit_byte(chunk, (Op::BuildList(count), line(self.previous.line)));
+    }
+
+    pub fn map(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
+        let mut count = 0;
+        if self.current.token_type != TokenType::RightBrace {
+            loop {
+                self.expression(source, chunk, scanner, heap, false);
+                self.consume(source, TokenType::Colon, "Expect ':' after map key.", scanner);
+                self.expression(source, chunk, scanner, heap, false);
+                if count == 255 {
+                    self.error_at_previous("Can't have more than 255 entries in a map literal.");
+                }
+                count += 1;
+                if !self.match_token(TokenType::Comma, source, scanner) {
+                    break;
+                }
+            }
+        }
+        self.consume(source, TokenType::RightBrace, "Expect '}' after map entries.", scanner);
+        self.emit_byte(chunk, (Op::BuildMap(count), line(self.previous.line)));
+    }
+
+    pub fn subscript(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, can_assign: bool) {
+        self.expression(source, chunk, scanner, heap, false);
+        self.consume(source, TokenType::RightBracket, "Expect ']' after index.", scanner);
+        if can_assign && self.match_token(TokenType::Equal, source, scanner) {
+            self.expression(source, chunk, scanner, heap, false);
+            self.emit_byte(chunk, (Op::IndexSet, line(self.previous.line)));
+        } else if let Some(op) = self.match_compound_assignment(source, scanner, can_assign) {
+            self.emit_bytes(chunk, (Op::Dup2, line(self.previous.line)), (Op::IndexGet, line(self.previous.line)));
+            self.expression(source, chunk, scanner, heap, false);
+            self.emit_bytes(chunk, (op, line(self.previous.line)), (Op::IndexSet, line(self.previous.line)));
+        } else if let Some(op) = self.take_prefix_increment() {
+            self.emit_bytes(chunk, (Op::Dup2, line(self.previous.line)), (Op::IndexGet, line(self.previous.line)));
+            self.emit_constant(chunk, Number(1.0), line(self.previous.line));
+            self.emit_bytes(chunk, (op, line(self.previous.line)), (Op::IndexSet, line(self.previous.line)));
+        } else if let Some(op) = self.match_postfix_increment(source, scanner) {
+            // Keep a copy of the old value under the target and index so it's what's left afterwards.
+            self.emit_bytes(chunk, (Op::Dup2, line(self.previous.line)), (Op::IndexGet, line(self.previous.line)));
+            self.emit_bytes(chunk, (Op::Dup, line(self.previous.line)), (Op::Bury(3), line(self.previous.line)));
+            self.emit_constant(chunk, Number(1.0), line(self.previous.line));
+            self.emit_bytes(chunk, (op, line(self.previous.line)), (Op::IndexSet, line(self.previous.line)));
+            self.emit_byte(chunk, (Op::Pop, line(self.previous.line)));
+        } else {
+            self.emit_byte(chunk, (Op::IndexGet, line(self.previous.line)));
+        }
+    }
+
+    pub fn prefix_increment(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
+        let op = if self.previous.token_type == TokenType::PlusPlus { Op::Add } else { Op::Subtract };
+        self.prefix_increment = Some(op);
+        self.parse_precedence(source, chunk, Precedence::Call, scanner, heap);
+        if self.prefix_increment.take().is_some() {
+            self.error_at_previous("Invalid increment target.");
+        }
+    }
+
+    pub fn binary(&mut self, source: &str, chunk: &mut Chunk, scanner: &mut scanner::Scanner, heap: &mut Heap, _can_assign: bool) {
         let operator_type = self.previous.clone().token_type;
         let rule = self.get_rule(&operator_type);
-        self.parse_precedence(source, chunk, rule.precedence.clone().increase(), scanner, heap);
+        // ** is right-associative, so its right operand may itself be another **.
+        let operand_precedence = if operator_type == TokenType::StarStar {
+            rule.precedence
+        } else {
+            rule.precedence.increase()
+        };
+        self.parse_precedence(source, chunk, operand_precedence, scanner, heap);
         match operator_type {
             TokenType::BangEqual => {
                 self.emit_bytes(chunk, (Op::Equal, line(self.previous.line)), (Op::Not, line(self.previous.line)));
@@ -584,6 +1122,13 @@ impl Parser {
             TokenType::Minus => self.emit_byte(chunk, (Op::Subtract, line(self.previous.line))),
             TokenType::Star => self.emit_byte(chunk, (Op::Multiply, line(self.previous.line))),
             TokenType::Slash => self.emit_byte(chunk, (Op::Divide, line(self.previous.line))),
+            TokenType::Percent => self.emit_byte(chunk, (Op::Modulo, line(self.previous.line))),
+            TokenType::StarStar => self.emit_byte(chunk, (Op::Power, line(self.previous.line))),
+            TokenType::Ampersand => self.emit_byte(chunk, (Op::BitAnd, line(self.previous.line))),
+            TokenType::Pipe => self.emit_byte(chunk, (Op::BitOr, line(self.previous.line))),
+            TokenType::Caret => self.emit_byte(chunk, (Op::BitXor, line(self.previous.line))),
+            TokenType::LessLess => self.emit_byte(chunk, (Op::ShiftLeft, line(self.previous.line))),
+            TokenType::GreaterGreater => self.emit_byte(chunk, (Op::ShiftRight, line(self.previous.line))),
             _ => (),
         }
 
@@ -593,8 +1138,8 @@ impl Parser {
         match token_type {
             TokenType::LeftParen => ParseRule {
                 prefix: Some(Parser::grouping),
-                infix: None,
-                precedence: Precedence::None,
+                infix: Some(Parser::call),
+                precedence: Precedence::Call,
             },
             TokenType::RightParen => ParseRule {
                 prefix: None,
@@ -602,7 +1147,7 @@ impl Parser {
                 precedence: Precedence::None,
             },
             TokenType::LeftBrace => ParseRule {
-                prefix: None,
+                prefix: Some(Parser::map),
                 infix: None,
                 precedence: Precedence::None,
             },
@@ -611,6 +1156,16 @@ impl Parser {
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenType::LeftBracket => ParseRule {
+                prefix: Some(Parser::list),
+                infix: Some(Parser::subscript),
+                precedence: Precedence::Call,
+            },
+            TokenType::RightBracket => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::Comma => ParseRule {
                 prefix: None,
                 infix: None,
@@ -631,6 +1186,21 @@ impl Parser {
                 infix: Some(Parser::binary),
                 precedence: Precedence::Term,
             },
+            TokenType::Question => ParseRule {
+                prefix: None,
+                infix: Some(Parser::conditional),
+                precedence: Precedence::Conditional,
+            },
+            TokenType::QuestionQuestion => ParseRule {
+                prefix: None,
+                infix: Some(Parser::coalesce),
+                precedence: Precedence::Coalesce,
+            },
+            TokenType::Colon => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::Semicolon => ParseRule {
                 prefix: None,
                 infix: None,
@@ -646,6 +1216,57 @@ impl Parser {
                 infix: Some(Parser::binary),
                 precedence: Precedence::Factor,
             },
+            TokenType::PlusPlus | TokenType::MinusMinus => ParseRule {
+                prefix: Some(Parser::prefix_increment),
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenType::PlusEqual | TokenType::MinusEqual | TokenType::StarEqual
+                | TokenType::SlashEqual | TokenType::PercentEqual => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenType::Percent => ParseRule {
+                prefix: None,
+                infix: Some(Parser::binary),
+                precedence: Precedence::Factor,
+            },
+            TokenType::StarStar => ParseRule {
+                prefix: None,
+                infix: Some(Parser::binary),
+                precedence: Precedence::Exponent,
+            },
+            TokenType::Ampersand => ParseRule {
+                prefix: None,
+                infix: Some(Parser::binary),
+                precedence: Precedence::BitAnd,
+            },
+            TokenType::Pipe => ParseRule {
+                prefix: None,
+                infix: Some(Parser::binary),
+                precedence: Precedence::BitOr,
+            },
+            TokenType::Caret => ParseRule {
+                prefix: None,
+                infix: Some(Parser::binary),
+                precedence: Precedence::BitXor,
+            },
+            TokenType::Tilde => ParseRule {
+                prefix: Some(Parser::unary),
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenType::LessLess => ParseRule {
+                prefix: None,
+                infix: Some(Parser::binary),
+                precedence: Precedence::Shift,
+            },
+            TokenType::GreaterGreater => ParseRule {
+                prefix: None,
+                infix: Some(Parser::binary),
+                precedence: Precedence::Shift,
+            },
             TokenType::Bang => ParseRule {
                 prefix: Some(Parser::unary),
                 infix: None,
@@ -706,11 +1327,31 @@ impl Parser {
                 infix: Some(Parser::and),
                 precedence: Precedence::And,
             },
+            TokenType::Break => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenType::Catch => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::Class => ParseRule {
                 prefix: None,
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenType::Const => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenType::Continue => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::Else => ParseRule {
                 prefix: None,
                 infix: None,
@@ -721,16 +1362,36 @@ impl Parser {
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenType::Finally => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::For => ParseRule {
                 prefix: None,
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenType::From => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::Fun => ParseRule {
                 prefix: None,
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenType::Import => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenType::In => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::If => ParseRule {
                 prefix: None,
                 infix: None,
@@ -766,11 +1427,21 @@ impl Parser {
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenType::Throw => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::True => ParseRule {
                 prefix: Some(Parser::literal),
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenType::Try => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::Var => ParseRule {
                 prefix: None,
                 infix: None,
@@ -786,7 +1457,7 @@ impl Parser {
                 infix: None,
                 precedence: Precedence::None,
             },
-            TokenType::EOF => ParseRule {
+            TokenType::Eof => ParseRule {
                 prefix: None,
                 infix: None,
                 precedence: Precedence::None,
@@ -794,7 +1465,7 @@ impl Parser {
         }
     }
 
-    pub fn parse_precedence(&mut self, source: &String, chunk: &mut Chunk, precedence: Precedence, scanner: &mut scanner::Scanner, heap: &mut Heap) {
+    pub fn parse_precedence(&mut self, source: &str, chunk: &mut Chunk, precedence: Precedence, scanner: &mut scanner::Scanner, heap: &mut Heap) {
         self.advance(source, scanner);
         let prefix_rule = self.get_rule(&self.previous.token_type).prefix;
         let can_assign = precedence <= Precedence::Assignment;
@@ -806,8 +1477,12 @@ impl Parser {
             self.advance(source, scanner);
             let infix_rule = self.get_rule(&self.previous.token_type).infix;
             if let Some(infix) = infix_rule {
-                infix(self, source, chunk, scanner, heap, false);
+                infix(self, source, chunk, scanner, heap, can_assign);
             }
         }
+
+        if can_assign && (self.match_token(TokenType::Equal, source, scanner) || self.match_compound_assignment(source, scanner, can_assign).is_some()) {
+            self.error_at_previous("Invalid assignment target.");
+        }
     }
 }
\ No newline at end of file
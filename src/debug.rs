@@ -1,125 +1,273 @@
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
 use crate::chunk::{
-    Chunk, Op, Op::*, Line,
+    Chunk, Op, Op::*,
 };
-use crate::value::Value;
-use crate::object::Heap;
+use crate::value::{FormatMode, Value};
+use crate::object::{Heap, HeapData};
 
-pub fn _dissassemble_chunk(chunk: &Chunk, heap: &mut Heap, name: &str) {
-    println!("== {} ==", name);
+pub struct Instruction {
+    pub offset: usize,
+    pub line: usize,
+    pub same_line: bool,
+    pub name: &'static str,
+    pub operand: Operand,
+}
 
-    let mut offset = 0;
-    while offset < chunk.code.len() {
-        offset = dissassemble_instruction(chunk, heap, offset);
-        println!();
-    }
+pub enum Operand {
+    None,
+    Constant(usize, String),
+    Byte(usize),
+    Jump(usize, isize),
 }
 
-pub fn dissassemble_instruction(chunk: &Chunk, heap: &Heap, offset: usize) -> usize {
-    print!("{:04} ", offset);
-    let (instruction, line) = chunk.code.get(offset).unwrap();
-    match instruction {
-        Constant(const_idx) => constant_instruction(chunk, heap, offset, line, "OP_CONSTANT", const_idx),
-        Op::Nil => simple_instruction(chunk, offset, line, "OP_NIL"),
-        Op::True => simple_instruction(chunk, offset, line, "OP_TRUE"),
-        Op::False => simple_instruction(chunk, offset, line, "OP_FALSE"),
-        Pop => simple_instruction(chunk, offset, line, "OP_POP"),
-        GetLocal(_) => byte_instruction(chunk, offset, line, "OP_GET_LOCAL"),
-        SetLocal(_) => byte_instruction(chunk, offset, line, "OP_SET_LOCAL"),
-        GetGlobal(const_idx) => constant_instruction(chunk, heap, offset, line, "OP_GET_GLOBAL", const_idx),
-        DefineGlobal(const_idx) => constant_instruction(chunk, heap, offset, line, "OP_DEFINE_GLOBAL", const_idx),
-        SetGlobal(const_idx) => constant_instruction(chunk, heap, offset, line, "OP_SET_GLOBAL", const_idx),
-        Equal => simple_instruction(chunk, offset, line, "OP_EQUAL"),
-        Greater => simple_instruction(chunk, offset, line, "OP_GREATER"),
-        Less => simple_instruction(chunk, offset, line, "OP_LESS"),
-        Add => simple_instruction(chunk, offset, line, "OP_ADD"),
-        Subtract => simple_instruction(chunk, offset, line, "OP_SUBTRACT"),
-        Multiply => simple_instruction(chunk, offset, line, "OP_MULTIPLY"),
-        Divide => simple_instruction(chunk, offset, line, "OP_DIVIDE"),
-        Not => simple_instruction(chunk, offset, line, "OP_NOT"),
-        Negate => simple_instruction(chunk, offset, line, "OP_NEGATE"),
-        Print => {
-            let size = simple_instruction(chunk, offset, line, "OP_PRINT");
-            size
-        },
-        JumpIfFalse(_) => jump_instruction(chunk, offset, line, "OP_JUMP_IF_FALSE", 1),
-        Jump(_) => jump_instruction(chunk, offset, line, "OP_JUMP", 1),
-        Loop(_) => jump_instruction(chunk, offset, line, "OP_LOOP", -1),
-        Return => simple_instruction(chunk, offset, line, "OP_RETURN"),
+pub struct TraceConfig {
+    pub enabled: bool,
+    pub renderer: Box<dyn Renderer>,
+    pub out: Box<dyn Write + Send>,
+    pub functions: Vec<String>,
+    pub lines: Option<RangeInclusive<usize>>,
+    pub ops: Vec<String>,
+    pub max_instructions: Option<usize>,
+    traced: usize,
+}
+
+impl TraceConfig {
+    pub fn new() -> TraceConfig {
+        TraceConfig {
+            enabled: false,
+            renderer: Box::new(TextRenderer),
+            out: Box::new(io::stdout()),
+            functions: Vec::new(),
+            lines: None,
+            ops: Vec::new(),
+            max_instructions: None,
+            traced: 0,
+        }
+    }
+
+    pub fn add_op_filter(&mut self, op: &str) {
+        let op = op.to_uppercase();
+        if op.starts_with("OP_") {
+            self.ops.push(op);
+        } else {
+            self.ops.push(format!("OP_{}", op));
+        }
+    }
+
+    pub fn accepts(&self, function: &str, instruction: &Instruction) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        if let Some(max) = self.max_instructions {
+            if self.traced >= max {
+                return false;
+            }
+        }
+        if !self.functions.is_empty() && !self.functions.iter().any(|name| name == function) {
+            return false;
+        }
+        if let Some(lines) = &self.lines {
+            if !lines.contains(&instruction.line) {
+                return false;
+            }
+        }
+        self.ops.is_empty() || self.ops.iter().any(|op| op == instruction.name)
+    }
+
+    pub fn write(&mut self, stack: &[String], instruction: &Instruction) {
+        let output = self.renderer.trace(stack, instruction);
+        self.traced += 1;
+        // A closed trace sink shouldn't stop the program being traced.
+        let _ = self.out.write_all(output.as_bytes());
+        let _ = self.out.flush();
     }
 }
 
-fn simple_instruction(chunk: &Chunk, offset: usize, line: &Line, name: &str) -> usize {
-    let mut line_no = format!("{:04}", line.value);
-    let previous_offset = if offset == 0 { 0 } else { offset - 1 };
-    if previous_offset != offset && chunk.code.get(offset - 1).unwrap().1.value == line.value {
-        line_no = "   |".to_string();
+pub trait Renderer: Send {
+    fn chunk_header(&self, name: &str) -> String;
+    fn instruction(&self, instruction: &Instruction) -> String;
+    fn trace(&self, stack: &[String], instruction: &Instruction) -> String;
+}
+
+pub struct TextRenderer;
+
+impl Renderer for TextRenderer {
+    fn chunk_header(&self, name: &str) -> String {
+        format!("== {} ==\n", name)
     }
-    print!("\r {:4} {:<16}", line_no, name);
-    for _ in 0..16 {
-        print!(" ");
+
+    fn instruction(&self, instruction: &Instruction) -> String {
+        let line_no = if instruction.same_line {
+            "   |".to_string()
+        } else {
+            format!("{:04}", instruction.line)
+        };
+        let operand = match &instruction.operand {
+            Operand::None => String::new(),
+            Operand::Constant(const_idx, value) => format!(" {:4} '{}'", const_idx, value),
+            Operand::Byte(byte) => format!(" {:4}", byte),
+            Operand::Jump(jump, target) => format!(" {:4} -> {:04}", jump, target),
+        };
+        let output = format!("{:04}  {:4} {:<16}{}", instruction.offset, line_no, instruction.name, operand);
+        format!("{}\n", output.trim_end())
+    }
+
+    fn trace(&self, stack: &[String], instruction: &Instruction) -> String {
+        let mut output = String::from("          ");
+        for value in stack {
+            output.push_str(&format!("[ {} ]", value));
+        }
+        output.push('\n');
+        output.push_str(&self.instruction(instruction));
+        output
     }
-    offset + 1
 }
 
-fn constant_instruction(chunk: &Chunk, heap: &Heap, offset: usize, line: &Line, name: &str, const_idx: &usize) -> usize {
-    let mut line_no = format!("{:04}", line.value);
-    let previous_offset = if offset == 0 { 0 } else { offset - 1 };
-    if previous_offset != offset && chunk.code.get(offset - 1).unwrap().1.value == line.value {
-        line_no = "   |".to_string();
+pub struct JsonRenderer;
+
+impl JsonRenderer {
+    fn fields(&self, instruction: &Instruction) -> String {
+        let mut fields = format!(
+            "\"offset\":{},\"line\":{},\"op\":{}",
+            instruction.offset, instruction.line, json_string(instruction.name)
+        );
+        match &instruction.operand {
+            Operand::None => (),
+            Operand::Constant(const_idx, value) => {
+                fields.push_str(&format!(",\"constant\":{},\"value\":{}", const_idx, json_string(value)));
+            },
+            Operand::Byte(byte) => {
+                fields.push_str(&format!(",\"operand\":{}", byte));
+            },
+            Operand::Jump(jump, target) => {
+                fields.push_str(&format!(",\"jump\":{},\"target\":{}", jump, target));
+            },
+        }
+        fields
+    }
+}
+
+impl Renderer for JsonRenderer {
+    fn chunk_header(&self, name: &str) -> String {
+        format!("{{\"chunk\":{}}}\n", json_string(name))
+    }
+
+    fn instruction(&self, instruction: &Instruction) -> String {
+        format!("{{{}}}\n", self.fields(instruction))
+    }
+
+    fn trace(&self, stack: &[String], instruction: &Instruction) -> String {
+        let stack = stack.iter().map(|value| json_string(value)).collect::<Vec<_>>().join(",");
+        format!("{{{},\"stack\":[{}]}}\n", self.fields(instruction), stack)
     }
-    print!("\r {:4} {:<16} {:4} '", line_no, name, const_idx);
-    let val_len = print_value(chunk.constants.get(*const_idx).unwrap(), heap);
-    print!("'");
-    if val_len < 8 {
-        for _ in 0..(8 - val_len) {
-            print!(" ");
+}
+
+fn json_string(value: &str) -> String {
+    let mut output = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if (c as u32) < 0x20 => output.push_str(&format!("\\u{:04x}", c as u32)),
+            c => output.push(c),
         }
     }
-    offset + 1
+    output.push('"');
+    output
 }
 
-fn byte_instruction(chunk: &Chunk, offset: usize, line: &Line, name: &str) -> usize {
-    let mut line_no = format!("{:04}", line.value);
-    let previous_offset = if offset == 0 { 0 } else { offset - 1 };
-    if previous_offset != offset && chunk.code.get(offset - 1).unwrap().1.value == line.value {
-        line_no = "   |".to_string();
+pub fn dissassemble_program(chunk: &Chunk, heap: &Heap, name: &str, renderer: &dyn Renderer) -> String {
+    let mut output = dissassemble_chunk(chunk, heap, name, renderer);
+
+    for constant in chunk.constants.iter() {
+        if let Value::Obj(id) = constant {
+            if let Some(HeapData::ObjFunction(function)) = heap.get(id) {
+                output.push_str(&dissassemble_program(function.chunk(), heap, function.name(), renderer));
+            }
+        }
     }
-    let byte = chunk.code.get(offset).unwrap();
-    print!("\r {:4} {:<20} {:4}", line_no, name, byte.0);
-    offset + 2
+    output
 }
 
-fn jump_instruction(chunk: &Chunk, offset: usize, line: &Line, name: &str, sign: i32) -> usize {
-    let jump = chunk.code.get(offset).unwrap();
-    let mut line_no = format!("{:04}", line.value);
-    let previous_offset = if offset == 0 { 0 } else { offset - 1 };
-    if previous_offset != offset && chunk.code.get(offset - 1).unwrap().1.value == line.value {
-        line_no = "   |".to_string();
+pub fn dissassemble_chunk(chunk: &Chunk, heap: &Heap, name: &str, renderer: &dyn Renderer) -> String {
+    let mut output = renderer.chunk_header(name);
+    for offset in 0..chunk.code.len() {
+        output.push_str(&renderer.instruction(&dissassemble_instruction(chunk, heap, offset)));
     }
-    print!("\r {:4} {:<16} {} -> {}", line_no, name, jump.0, offset as i32 + 2 + sign);
-    offset + 2
+    output
 }
 
-pub fn print_value(value: &Value, heap: &Heap) -> usize {
-    match value {
-        Value::Number(num) => {
-            let val = format!("{}", num);
-            print!("\r{val}");
-            return val.len();
-        },
-        Value::Nil => {
-            print!("\rnil");
-            return 3;
-        },
-        Value::Bool(b) => {
-            let val = format!("{}", b);
-            print!("\r{val}");
-            return val.len();
-        },
-        Value::Obj(val) => {
-            let obj_string = heap.get(val).unwrap();
-            print!("\r{}", obj_string.as_string());
-            return obj_string.as_string().len();
-        },
+pub fn dissassemble_instruction(chunk: &Chunk, heap: &Heap, offset: usize) -> Instruction {
+    let (instruction, line) = chunk.code.get(offset).unwrap();
+    let (name, operand) = match instruction {
+        Constant(const_idx) => ("OP_CONSTANT", constant_operand(chunk, heap, const_idx)),
+        Op::Nil => ("OP_NIL", Operand::None),
+        Op::True => ("OP_TRUE", Operand::None),
+        Op::False => ("OP_FALSE", Operand::None),
+        Pop => ("OP_POP", Operand::None),
+        Dup => ("OP_DUP", Operand::None),
+        Dup2 => ("OP_DUP2", Operand::None),
+        Bury(depth) => ("OP_BURY", Operand::Byte(*depth)),
+        GetLocal(slot) => ("OP_GET_LOCAL", Operand::Byte(*slot)),
+        SetLocal(slot) => ("OP_SET_LOCAL", Operand::Byte(*slot)),
+        GetGlobal(const_idx) => ("OP_GET_GLOBAL", constant_operand(chunk, heap, const_idx)),
+        DefineConstant(const_idx) => ("OP_DEFINE_CONSTANT", constant_operand(chunk, heap, const_idx)),
+        DefineGlobal(const_idx) => ("OP_DEFINE_GLOBAL", constant_operand(chunk, heap, const_idx)),
+        SetGlobal(const_idx) => ("OP_SET_GLOBAL", constant_operand(chunk, heap, const_idx)),
+        Equal => ("OP_EQUAL", Operand::None),
+        Greater => ("OP_GREATER", Operand::None),
+        Less => ("OP_LESS", Operand::None),
+        Add => ("OP_ADD", Operand::None),
+        Subtract => ("OP_SUBTRACT", Operand::None),
+        Multiply => ("OP_MULTIPLY", Operand::None),
+        Divide => ("OP_DIVIDE", Operand::None),
+        Modulo => ("OP_MODULO", Operand::None),
+        Power => ("OP_POWER", Operand::None),
+        BitAnd => ("OP_BIT_AND", Operand::None),
+        BitOr => ("OP_BIT_OR", Operand::None),
+        BitXor => ("OP_BIT_XOR", Operand::None),
+        BitNot => ("OP_BIT_NOT", Operand::None),
+        ShiftLeft => ("OP_SHIFT_LEFT", Operand::None),
+        ShiftRight => ("OP_SHIFT_RIGHT", Operand::None),
+        Not => ("OP_NOT", Operand::None),
+        Negate => ("OP_NEGATE", Operand::None),
+        Print => ("OP_PRINT", Operand::None),
+        JumpIfFalse(jump) => ("OP_JUMP_IF_FALSE", jump_operand(offset, 1, jump)),
+        JumpIfNotNil(jump) => ("OP_JUMP_IF_NOT_NIL", jump_operand(offset, 1, jump)),
+        Jump(jump) => ("OP_JUMP", jump_operand(offset, 1, jump)),
+        Loop(jump) => ("OP_LOOP", jump_operand(offset, -1, jump)),
+        Call(arg_count) => ("OP_CALL", Operand::Byte(*arg_count)),
+        BuildList(count) => ("OP_BUILD_LIST", Operand::Byte(*count)),
+        BuildMap(count) => ("OP_BUILD_MAP", Operand::Byte(*count)),
+        IterInit => ("OP_ITER_INIT", Operand::None),
+        IterNext(slot) => ("OP_ITER_NEXT", Operand::Byte(*slot)),
+        IndexGet => ("OP_INDEX_GET", Operand::None),
+        IndexSet => ("OP_INDEX_SET", Operand::None),
+        Throw => ("OP_THROW", Operand::None),
+        EndFinally => ("OP_END_FINALLY", Operand::None),
+        Import(const_idx) => ("OP_IMPORT", constant_operand(chunk, heap, const_idx)),
+        ImportName(const_idx) => ("OP_IMPORT_NAME", constant_operand(chunk, heap, const_idx)),
+        Return => ("OP_RETURN", Operand::None),
+    };
+
+    Instruction {
+        offset,
+        line: line.value,
+        same_line: offset > 0 && chunk.code[offset - 1].1.value == line.value,
+        name,
+        operand,
     }
-}
\ No newline at end of file
+}
+
+fn constant_operand(chunk: &Chunk, heap: &Heap, const_idx: &usize) -> Operand {
+    Operand::Constant(*const_idx, heap.format_value(chunk.constants.get(*const_idx).unwrap(), FormatMode::Display))
+}
+
+fn jump_operand(offset: usize, sign: isize, jump: &usize) -> Operand {
+    Operand::Jump(*jump, offset as isize + 1 + sign * *jump as isize)
+}
@@ -0,0 +1,111 @@
+use crossterm::style::{Attribute, Color, Stylize};
+
+use crate::scanner::{Scanner, Token, TokenType, KEYWORDS};
+
+pub fn highlight(source: &str, cursor: usize) -> String {
+    // The scanner mixes byte and char offsets, so only ASCII lines are safe to colour.
+    if !source.is_ascii() {
+        return source.to_string();
+    }
+
+    let chars: Vec<char> = source.chars().collect();
+    let mut styles: Vec<Option<Color>> = vec![None; chars.len()];
+    let mut tokens: Vec<Token> = Vec::new();
+
+    let mut scanner = Scanner::new();
+    loop {
+        let (_, gap_start) = scanner.span();
+        let token = scanner.scan_token(source);
+        let (start, end) = scanner.span();
+
+        if let Some(comment) = source[gap_start..start].find("//") {
+            for style in styles.iter_mut().take(start).skip(gap_start + comment) {
+                *style = Some(Color::DarkGrey);
+            }
+        }
+
+        let color = match token.token_type {
+            TokenType::Eof => break,
+            TokenType::String => Some(Color::Green),
+            TokenType::Number => Some(Color::Yellow),
+            TokenType::Error => Some(Color::Red),
+            _ if KEYWORDS.contains(&&source[start..end]) => Some(Color::Magenta),
+            _ => None,
+        };
+        for style in styles.iter_mut().take(end).skip(start) {
+            *style = color;
+        }
+
+        if token.token_type == TokenType::Error {
+            break;
+        }
+        tokens.push(token);
+    }
+
+    let brackets = matching_brackets(&tokens, &chars, cursor);
+
+    let mut output = String::new();
+    let mut idx = 0;
+    while idx < chars.len() {
+        let style = (styles[idx], brackets.contains(&idx));
+        let run_start = idx;
+        while idx < chars.len() && (styles[idx], brackets.contains(&idx)) == style {
+            idx += 1;
+        }
+
+        let mut styled = chars[run_start..idx].iter().collect::<String>().stylize();
+        if let Some(color) = style.0 {
+            styled = styled.with(color);
+        }
+        if style.1 {
+            styled = styled.attribute(Attribute::Reverse);
+        }
+        output.push_str(&styled.to_string());
+    }
+    output
+}
+
+fn bracket_direction(token_type: &TokenType) -> Option<(isize, TokenType)> {
+    match token_type {
+        TokenType::LeftParen => Some((1, TokenType::RightParen)),
+        TokenType::LeftBrace => Some((1, TokenType::RightBrace)),
+        TokenType::LeftBracket => Some((1, TokenType::RightBracket)),
+        TokenType::RightParen => Some((-1, TokenType::LeftParen)),
+        TokenType::RightBrace => Some((-1, TokenType::LeftBrace)),
+        TokenType::RightBracket => Some((-1, TokenType::LeftBracket)),
+        _ => None,
+    }
+}
+
+fn matching_brackets(tokens: &[Token], chars: &[char], cursor: usize) -> Vec<usize> {
+    // Prefer the bracket just before the cursor, then the one under it.
+    let candidates = [cursor.checked_sub(1), Some(cursor)];
+    let found = candidates.iter().flatten().find_map(|&pos| {
+        if pos >= chars.len() {
+            return None;
+        }
+        tokens.iter().position(|token| token.start == pos && bracket_direction(&token.token_type).is_some())
+    });
+
+    let Some(open_idx) = found else {
+        return Vec::new();
+    };
+    let (step, closing) = bracket_direction(&tokens[open_idx].token_type).unwrap();
+    let opening = tokens[open_idx].token_type.clone();
+
+    let mut depth = 0;
+    let mut idx = open_idx as isize;
+    while idx >= 0 && (idx as usize) < tokens.len() {
+        let token = &tokens[idx as usize];
+        if token.token_type == opening {
+            depth += 1;
+        } else if token.token_type == closing {
+            depth -= 1;
+            if depth == 0 {
+                return vec![tokens[open_idx].start, token.start];
+            }
+        }
+        idx += step;
+    }
+    Vec::new()
+}
@@ -1,18 +1,16 @@
 // External Crates
 extern crate clap;
 
-use clap::{Arg, Command};
-use crossterm::{
-    event::{self, Event, KeyCode},
-    terminal::{enable_raw_mode, disable_raw_mode},
-    cursor,
-    ExecutableCommand,
-};
+use clap::{Arg, ArgAction, Command};
 
 // Standard Package
 
-use std::io::{self, Write};
 use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use debug::{JsonRenderer, Renderer, TextRenderer};
 
 // Internal Modules
 
@@ -23,8 +21,14 @@ mod vm;
 mod compile;
 mod scanner;
 mod object;
+mod highlight;
+mod repl;
+mod native;
+mod output;
 
 const INPUT: &str = "Script";
+const ARGS: &str = "Args";
+const EVAL: &str = "eval";
 
 fn get_input(matches: &clap::ArgMatches) -> Option<&String> {
     if let Some(file_input) = matches.get_one::<String>(INPUT) {
@@ -33,6 +37,13 @@ fn get_input(matches: &clap::ArgMatches) -> Option<&String> {
     None
 }
 
+fn get_script_args(matches: &clap::ArgMatches) -> Vec<String> {
+    match matches.get_many::<String>(ARGS) {
+        Some(args) => args.cloned().collect(),
+        None => Vec::new(),
+    }
+}
+
 fn main() {
     let mut vm: vm::VM = vm::VM::new();
     vm.init_vm();
@@ -42,177 +53,223 @@ fn main() {
         .author("Sam Ehlers")
         .about("A lox interpreter created from the book Crafting Interpreters")
         .arg(Arg::new(INPUT)
-            .help("lox script to run")
+            .help("lox script to run, or - to read it from stdin")
             .required(false)
             .index(1))
+        .arg(Arg::new(ARGS)
+            .help("Arguments passed to the script, readable through args()")
+            .index(2)
+            .num_args(0..)
+            .trailing_var_arg(true)
+            .allow_hyphen_values(true))
+        .arg(Arg::new(EVAL)
+            .help("Evaluates the given source instead of a script")
+            .short('e')
+            .long("eval")
+            .value_name("CODE"))
         .arg(Arg::new("debug")
             .help("Prints debug information")
             .short('d')
             .long("debug")
             .num_args(0))
+        .arg(Arg::new("trace-out")
+            .help("Writes an execution trace to the given file as JSON lines")
+            .long("trace-out")
+            .value_name("FILE"))
+        .arg(Arg::new("trace-function")
+            .help("Only traces instructions run inside the named function")
+            .long("trace-function")
+            .value_name("NAME")
+            .action(ArgAction::Append))
+        .arg(Arg::new("trace-lines")
+            .help("Only traces instructions from the given source lines, e.g. 10-20")
+            .long("trace-lines")
+            .value_name("RANGE"))
+        .arg(Arg::new("trace-op")
+            .help("Only traces the given opcode, e.g. add or OP_JUMP")
+            .long("trace-op")
+            .value_name("OP")
+            .action(ArgAction::Append))
+        .arg(Arg::new("trace-limit")
+            .help("Stops tracing after this many instructions")
+            .long("trace-limit")
+            .value_name("COUNT")
+            .value_parser(clap::value_parser!(usize)))
+        .arg(Arg::new("max-stack")
+            .help("Maximum number of values on the VM stack")
+            .long("max-stack")
+            .value_name("COUNT")
+            .value_parser(clap::value_parser!(usize)))
+        .arg(Arg::new("max-call-depth")
+            .help("Maximum depth of nested calls")
+            .long("max-call-depth")
+            .value_name("COUNT")
+            .value_parser(clap::value_parser!(usize)))
+        .arg(Arg::new("max-instructions")
+            .help("Stops the script after this many instructions")
+            .long("max-instructions")
+            .value_name("COUNT")
+            .value_parser(clap::value_parser!(usize)))
+        .arg(Arg::new("timeout")
+            .help("Stops the script after this many milliseconds")
+            .long("timeout")
+            .value_name("MILLIS")
+            .value_parser(clap::value_parser!(u64)))
+        .arg(Arg::new("max-memory")
+            .help("Maximum number of bytes the heap may hold")
+            .long("max-memory")
+            .value_name("BYTES")
+            .value_parser(clap::value_parser!(usize)))
+        .subcommand(Command::new("dis")
+            .about("Compiles a lox script without running it and prints its bytecode")
+            .arg(Arg::new(INPUT)
+                .help("lox script to disassemble")
+                .required(true)
+                .index(1))
+            .arg(Arg::new("json")
+                .help("Prints one JSON object per line instead of a text listing")
+                .long("json")
+                .num_args(0)))
         .get_matches();
 
-    if let Some(input) = get_input(&arg_matches) {
-        if arg_matches.get_flag("debug") {
-            unsafe {
-                vm::DEBUG_TRACE_EXECUTION = true;
-            }
+    if let Some(dis_matches) = arg_matches.subcommand_matches("dis") {
+        if let Some(input) = get_input(dis_matches) {
+            let renderer: Box<dyn Renderer> = if dis_matches.get_flag("json") {
+                Box::new(JsonRenderer)
+            } else {
+                Box::new(TextRenderer)
+            };
+            dissassemble_file(&mut vm, input, renderer.as_ref());
+        }
+        return;
+    }
+
+    configure_trace(&mut vm, &arg_matches);
+    if let Some(max_stack) = arg_matches.get_one::<usize>("max-stack") {
+        vm.limits.max_stack = *max_stack;
+    }
+    if let Some(max_call_depth) = arg_matches.get_one::<usize>("max-call-depth") {
+        vm.limits.max_call_depth = *max_call_depth;
+    }
+    vm.heap.max_bytes = arg_matches.get_one::<usize>("max-memory").copied();
+    vm.limits.max_instructions = arg_matches.get_one::<usize>("max-instructions").copied();
+    if let Some(timeout) = arg_matches.get_one::<u64>("timeout") {
+        vm.limits.timeout = Some(Duration::from_millis(*timeout));
+    }
+
+    let mut script_args = get_script_args(&arg_matches);
+    if let Some(code) = arg_matches.get_one::<String>(EVAL) {
+        if let Some(input) = get_input(&arg_matches) {
+            script_args.insert(0, input.clone());
         }
-        run_file(&mut vm, input);
+        vm.args = script_args;
+        run_source(&mut vm, code.clone());
+    } else if let Some(input) = get_input(&arg_matches) {
+        vm.args = script_args;
+        if input == "-" {
+            run_stdin(&mut vm);
+        } else {
+            run_file(&mut vm, input);
+        }
+    } else if !io::stdin().is_terminal() {
+        run_stdin(&mut vm);
     } else {
-        repl(&mut vm);
+        repl::repl(&mut vm);
     }
 
     drop(vm);
 }
 
-fn run_file(vm: &mut vm::VM, input: &String) {
+fn configure_trace(vm: &mut vm::VM, matches: &clap::ArgMatches) {
+    if matches.get_flag("debug") {
+        vm.trace.enabled = true;
+    }
+
+    if let Some(trace_file) = matches.get_one::<String>("trace-out") {
+        match fs::File::create(trace_file) {
+            Ok(file) => {
+                vm.trace.out = Box::new(io::BufWriter::new(file));
+                vm.trace.renderer = Box::new(JsonRenderer);
+                vm.trace.enabled = true;
+            },
+            Err(err) => {
+                eprintln!("Error creating trace file: {}", err);
+                std::process::exit(74);
+            }
+        }
+    }
+
+    if let Some(functions) = matches.get_many::<String>("trace-function") {
+        vm.trace.functions = functions.cloned().collect();
+    }
+    if let Some(ops) = matches.get_many::<String>("trace-op") {
+        for op in ops {
+            vm.trace.add_op_filter(op);
+        }
+    }
+    if let Some(lines) = matches.get_one::<String>("trace-lines") {
+        match parse_line_range(lines) {
+            Some(range) => vm.trace.lines = Some(range),
+            None => {
+                eprintln!("Invalid line range '{}', expected START-END.", lines);
+                std::process::exit(64);
+            }
+        }
+    }
+    vm.trace.max_instructions = matches.get_one::<usize>("trace-limit").copied();
+}
+
+fn parse_line_range(range: &str) -> Option<RangeInclusive<usize>> {
+    match range.split_once('-') {
+        Some((start, end)) => Some(start.trim().parse().ok()?..=end.trim().parse().ok()?),
+        None => {
+            let line = range.trim().parse().ok()?;
+            Some(line..=line)
+        }
+    }
+}
+
+fn run_file(vm: &mut vm::VM, input: &str) {
+    let source = read_file(input.to_string());
+    vm.script_path = fs::canonicalize(input).ok();
+    run_source(vm, source);
+}
+
+fn dissassemble_file(vm: &mut vm::VM, input: &str, renderer: &dyn Renderer) {
     let source = read_file(input.to_string());
+    match vm.compile(source) {
+        Some(chunk) => print!("{}", debug::dissassemble_program(&chunk, &vm.heap, "<script>", renderer)),
+        None => std::process::exit(65),
+    }
+}
+
+fn run_stdin(vm: &mut vm::VM) {
+    let mut source = String::new();
+    if let Err(err) = io::stdin().read_to_string(&mut source) {
+        eprintln!("Error reading stdin: {}", err);
+        std::process::exit(74);
+    }
+    run_source(vm, source);
+}
+
+fn run_source(vm: &mut vm::VM, source: String) {
     let result: vm::InterpretResult  = vm.interpret(source);
     match result {
         vm::InterpretResult::Ok => (),
         vm::InterpretResult::CompileError => std::process::exit(65),
-        vm::InterpretResult::RuntimeError => std::process::exit(70),
+        result => {
+            eprintln!("{}", result);
+            std::process::exit(70);
+        },
     }
 }
 
 fn read_file(file: String) -> String {
     match fs::read_to_string(file) {
-        Ok(input) => return input,
+        Ok(input) => input,
         Err(err) => {
             eprintln!("Error reading file: {}", err);
             std::process::exit(74);
         }
     }
 }
-
-fn repl(vm: &mut vm::VM) {
-    vm.init_vm();
-
-    let mut input_history: Vec<String> = Vec::new();
-    let mut current_input: String = String::new();
-    let mut history_index: Option<usize> = None;
-
-    enable_raw_mode().unwrap();
-    let mut stdout = io::stdout();
-    stdout.execute(crossterm::terminal::Clear(crossterm::terminal::ClearType::All)).unwrap();
-
-    print!("Welcome to Lox!\r\n");
-    print!("Type `exit` to exit the console.\r\n");
-    print!("> ");
-
-    loop {
-        io::stdout().flush().unwrap();        
-        if event::poll(std::time::Duration::from_millis(100)).unwrap() {
-            if let Event::Key(key_event) = event::read().unwrap() {
-                match key_event.code {
-                    KeyCode::Char(c) => {
-                        current_input.push(c);
-                        print!("{}", c);
-                        io::stdout().flush().unwrap();
-                    },
-                    KeyCode::Enter => {
-                        if !current_input.is_empty() {
-                            if current_input == "exit" {
-                                break;
-                            }
-                            if current_input == "stack" {
-                                current_input.clear();
-                                unsafe {
-                                    vm::DEBUG_TRACE_EXECUTION = true;
-                                }
-                                print!("\r\nFurther commands will show the stack...\r\n\r\n");
-                                print!("> ");
-                                continue;
-                            }
-                            if current_input == "nostack" {
-                                current_input.clear();
-                                unsafe {
-                                    vm::DEBUG_TRACE_EXECUTION = false;
-                                }
-                                print!("\r\nFurther commands will not show the stack...\r\n\r\n");
-                                print!("> ");
-                                continue;
-                            }
-                            print!("\r\n");
-                            input_history.push(current_input.clone());
-                            vm.interpret(current_input.clone());
-                            print!("\r\n");
-                            clear_line();
-                            print!("> ");
-                            current_input.clear();
-                            history_index = None;
-                        }
-                    },
-                    KeyCode::Backspace => {
-                        if !current_input.is_empty() {
-                            current_input.pop();
-                            print!("\r{}", clear_line());
-                            print!("> {}", current_input);
-                            io::stdout().flush().unwrap();
-                        }
-                    },
-                    KeyCode::Up => {
-                        if let Some(idx) = history_index {
-                            if idx > 0 {
-                                history_index = Some(idx - 1);
-                            }
-                        } else if !input_history.is_empty() {
-                            history_index = Some(input_history.len() - 1);
-                        }
-                        if let Some(idx) = history_index {
-                            current_input = input_history[idx].clone();
-                            print!("\r{}", clear_line());
-                            print!("> {}", current_input);
-                            io::stdout().flush().unwrap();
-                        }
-                    },
-                    KeyCode::Down => {
-                        if let Some(idx) = history_index {
-                            if idx < input_history.len() - 1 {
-                                history_index = Some(idx + 1);
-                            } else {
-                                history_index = None;
-                                current_input.clear();
-                                print!("\r{}", clear_line());
-                                print!("> {}", current_input);
-                            }
-                        }
-                        if let Some(idx) = history_index {
-                            current_input = input_history[idx].clone();
-                            print!("\r{}", clear_line());
-                            print!("> {}", current_input);
-                            io::stdout().flush().unwrap();
-                        }
-                    },
-                    KeyCode::Left => {
-                        if let Ok(_) = stdout.execute(cursor::MoveLeft(1)) {
-                            io::stdout().flush().unwrap();
-                        } else {
-                            if let Ok(_) = stdout.execute(cursor::MoveToPreviousLine(1)) {
-                                io::stdout().flush().unwrap();
-                            }
-                        }
-                    },
-                    KeyCode::Right => {
-                        if let Ok(_) = stdout.execute(cursor::MoveRight(1)) {
-                            io::stdout().flush().unwrap();
-                        } else {
-                            if let Ok(_) = stdout.execute(cursor::MoveToNextLine(1)) {
-                                io::stdout().flush().unwrap();
-                            }
-                        }
-                    },
-                    _ => (),
-                }
-            }
-        }
-    }
-    disable_raw_mode().unwrap();
-    println!();
-    println!("Goodbye!");
-}
-
-fn clear_line() -> &'static str {
-    "\x1b[2K\x1b[1G"
-}
\ No newline at end of file
@@ -0,0 +1,224 @@
+use crate::object::{grow_list, list_growth, HeapData, ObjMap, ObjRange};
+use crate::value::Value;
+use crate::vm::{list_index, modulo, VM};
+
+pub fn define_natives(vm: &mut VM) {
+    vm.define_native("args", args_native);
+    vm.define_native("len", len_native);
+    vm.define_native("push", push_native);
+    vm.define_native("pop", pop_native);
+    vm.define_native("insert", insert_native);
+    vm.define_native("remove", remove_native);
+    vm.define_native("slice", slice_native);
+    vm.define_native("keys", keys_native);
+    vm.define_native("values", values_native);
+    vm.define_native("has", has_native);
+    vm.define_native("delete", delete_native);
+    vm.define_native("range", range_native);
+    vm.define_native("idiv", idiv_native);
+}
+
+fn args_native(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    match args {
+        [] => Ok(Value::Number(vm.args.len() as f64)),
+        [Value::Number(index)] => {
+            if index.fract() != 0.0 || *index < 0.0 || *index as usize >= vm.args.len() {
+                return Ok(Value::Nil);
+            }
+            let arg = vm.args[*index as usize].clone();
+            vm.allocate(HeapData::String(arg)).map(Value::Obj)
+        },
+        [_] => Err("Argument index must be a number.".to_string()),
+        _ => Err(format!("Expected 0 or 1 arguments but got {}.", args.len())),
+    }
+}
+
+fn len_native(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    expect_args(args, 1)?;
+    let len = match &args[0] {
+        Value::Obj(heap_id) => match vm.heap.get(heap_id) {
+            Some(HeapData::String(string)) => string.chars().count(),
+            Some(HeapData::List(values)) => values.len(),
+            Some(HeapData::Map(map)) => map.entries().len(),
+            _ => return Err("Can only take the length of lists, maps and strings.".to_string()),
+        },
+        _ => return Err("Can only take the length of lists, maps and strings.".to_string()),
+    };
+    Ok(Value::Number(len as f64))
+}
+
+fn push_native(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    expect_args(args, 2)?;
+    let heap_id = list_arg(vm, &args[0])?;
+    reserve_element(vm, heap_id)?;
+    let value = args[1].clone();
+    update_list(vm, heap_id, |values| {
+        grow_list(values);
+        values.push(value);
+    });
+    Ok(Value::Nil)
+}
+
+fn pop_native(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    expect_args(args, 1)?;
+    let heap_id = list_arg(vm, &args[0])?;
+    update_list(vm, heap_id, |values| values.pop())
+        .ok_or_else(|| "Can't pop from an empty list.".to_string())
+}
+
+fn insert_native(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    expect_args(args, 3)?;
+    let heap_id = list_arg(vm, &args[0])?;
+    let len = list_len(vm, heap_id);
+    let index = match args[1] {
+        Value::Number(index) if index == len as f64 => len,
+        ref index => list_index(index, len)?,
+    };
+    reserve_element(vm, heap_id)?;
+    let value = args[2].clone();
+    update_list(vm, heap_id, |values| {
+        grow_list(values);
+        values.insert(index, value);
+    });
+    Ok(Value::Nil)
+}
+
+fn remove_native(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    expect_args(args, 2)?;
+    let heap_id = list_arg(vm, &args[0])?;
+    let index = list_index(&args[1], list_len(vm, heap_id))?;
+    Ok(update_list(vm, heap_id, |values| values.remove(index)))
+}
+
+fn slice_native(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    if args.len() != 2 && args.len() != 3 {
+        return Err(format!("Expected 2 or 3 arguments but got {}.", args.len()));
+    }
+    let heap_id = list_arg(vm, &args[0])?;
+    let len = list_len(vm, heap_id);
+    let start = slice_bound(&args[1], len)?;
+    let end = match args.get(2) {
+        Some(end) => slice_bound(end, len)?,
+        None => len,
+    };
+
+    let values = match vm.heap.get(&heap_id) {
+        Some(HeapData::List(values)) if start < end => values[start..end].to_vec(),
+        _ => Vec::new(),
+    };
+    vm.allocate(HeapData::List(values)).map(Value::Obj)
+}
+
+fn keys_native(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    expect_args(args, 1)?;
+    let keys = map_arg(vm, &args[0])?.entries().iter().map(|(key, _)| key.clone()).collect();
+    vm.allocate(HeapData::List(keys)).map(Value::Obj)
+}
+
+fn values_native(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    expect_args(args, 1)?;
+    let values = map_arg(vm, &args[0])?.entries().iter().map(|(_, value)| value.clone()).collect();
+    vm.allocate(HeapData::List(values)).map(Value::Obj)
+}
+
+fn has_native(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    expect_args(args, 2)?;
+    let key = vm.heap.map_key(&args[1])?;
+    Ok(Value::Bool(map_arg(vm, &args[0])?.contains(&key)))
+}
+
+fn delete_native(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    expect_args(args, 2)?;
+    let heap_id = map_id(vm, &args[0])?;
+    if map_arg(vm, &args[0])?.is_read_only() {
+        return Err("Module namespaces are read-only.".to_string());
+    }
+    let key = vm.heap.map_key(&args[1])?;
+    let removed = vm.heap.update(&heap_id, |data| match data {
+        HeapData::Map(map) => map.remove(&key),
+        _ => unreachable!("map_id only accepts maps"),
+    });
+    Ok(removed.flatten().unwrap_or(Value::Nil))
+}
+
+fn range_native(vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    let mut bounds = Vec::new();
+    for arg in args {
+        match arg {
+            Value::Number(num) => bounds.push(*num),
+            _ => return Err("Range bounds must be numbers.".to_string()),
+        }
+    }
+    let (start, end, step) = match bounds[..] {
+        [end] => (0.0, end, 1.0),
+        [start, end] => (start, end, 1.0),
+        [start, end, step] => (start, end, step),
+        _ => return Err(format!("Expected 1 to 3 arguments but got {}.", args.len())),
+    };
+    if step == 0.0 || step.is_nan() {
+        return Err("Range step can't be zero.".to_string());
+    }
+    vm.allocate(HeapData::Range(ObjRange { start, end, step })).map(Value::Obj)
+}
+
+// Floor division, the partner of `%`: `idiv(a, b) * b + a % b == a`.
+// There's no operator for it since `//` already starts a comment.
+fn idiv_native(_vm: &mut VM, args: &[Value]) -> Result<Value, String> {
+    expect_args(args, 2)?;
+    match (&args[0], &args[1]) {
+        (Value::Number(a), Value::Number(b)) => Ok(Value::Number((a - modulo(*a, *b)) / b)),
+        _ => Err("Operands must be numbers.".to_string()),
+    }
+}
+
+fn expect_args(args: &[Value], count: usize) -> Result<(), String> {
+    if args.len() != count {
+        return Err(format!("Expected {} arguments but got {}.", count, args.len()));
+    }
+    Ok(())
+}
+
+fn list_arg(vm: &VM, value: &Value) -> Result<usize, String> {
+    match value {
+        Value::Obj(heap_id) if vm.as_list(value).is_some() => Ok(*heap_id),
+        _ => Err("Expected a list.".to_string()),
+    }
+}
+
+fn map_id(vm: &VM, value: &Value) -> Result<usize, String> {
+    match value {
+        Value::Obj(heap_id) if vm.as_map(value).is_some() => Ok(*heap_id),
+        _ => Err("Expected a map.".to_string()),
+    }
+}
+
+fn map_arg<'a>(vm: &'a VM, value: &Value) -> Result<&'a ObjMap, String> {
+    vm.as_map(value).ok_or_else(|| "Expected a map.".to_string())
+}
+
+fn list_len(vm: &VM, heap_id: usize) -> usize {
+    vm.as_list(&Value::Obj(heap_id)).map_or(0, Vec::len)
+}
+
+fn reserve_element(vm: &mut VM, heap_id: usize) -> Result<(), String> {
+    let growth = vm.as_list(&Value::Obj(heap_id)).map_or(0, list_growth);
+    vm.reserve(growth)
+}
+
+fn update_list<T>(vm: &mut VM, heap_id: usize, update: impl FnOnce(&mut Vec<Value>) -> T) -> T {
+    vm.heap.update(&heap_id, |data| match data {
+        HeapData::List(values) => update(values),
+        _ => unreachable!("list_arg only accepts lists"),
+    }).unwrap()
+}
+
+fn slice_bound(bound: &Value, len: usize) -> Result<usize, String> {
+    let Value::Number(bound) = bound else {
+        return Err("Slice bounds must be numbers.".to_string());
+    };
+    if bound.fract() != 0.0 {
+        return Err("Slice bounds must be integers.".to_string());
+    }
+    let resolved = if *bound < 0.0 { *bound + len as f64 } else { *bound };
+    Ok(resolved.clamp(0.0, len as f64) as usize)
+}
@@ -1,32 +1,131 @@
 use std::collections::HashMap;
-use crate::chunk::Chunk;
+use std::mem::size_of;
+use crate::chunk::{Chunk, Line, Op};
+use crate::value::{FormatMode, MapKey, Value};
+use crate::vm::VM;
 
 pub struct Heap {
     bytes_allocated: usize,
     next_gc: usize,
+    pub max_bytes: Option<usize>,
     id_counter: usize,
     values: HashMap<usize, HeapVal>,
 }
 
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const INITIAL_NEXT_GC: usize = 1024 * 1024;
+const MIN_CAPACITY: usize = 4;
+
+// Lists and map entries grow by doubling through these, so the bytes a growth will cost are
+// known before it happens and can be checked against the memory cap.
+fn grown_capacity(len: usize, capacity: usize) -> usize {
+    if len < capacity {
+        capacity
+    } else {
+        (capacity * 2).max(MIN_CAPACITY)
+    }
+}
+
+pub fn list_growth(values: &Vec<Value>) -> usize {
+    (grown_capacity(values.len(), values.capacity()) - values.capacity()) * size_of::<Value>()
+}
+
+pub fn grow_list(values: &mut Vec<Value>) {
+    let capacity = grown_capacity(values.len(), values.capacity());
+    values.reserve_exact(capacity - values.len());
+}
+
 impl Heap {
     pub fn new() -> Heap {
         Heap {
             bytes_allocated: 0,
-            next_gc: 1024 * 1024,
+            next_gc: INITIAL_NEXT_GC,
+            max_bytes: None,
             id_counter: 0,
             values: HashMap::new(),
         }
     }
 
-    pub fn allocate(&mut self, data: HeapData) -> usize {
+    fn allocate(&mut self, data: HeapData) -> usize {
         let id = self.id_counter;
         self.id_counter += 1;
-        self.values.insert(id, HeapVal { marked: false, data });
+        let val = HeapVal::new(data);
+        self.bytes_allocated += val.size;
+        self.values.insert(id, val);
         id
     }
 
+    pub fn try_allocate(&mut self, data: HeapData) -> Result<usize, String> {
+        if !self.fits(data.size()) {
+            return Err("Out of memory.".to_string());
+        }
+        Ok(self.allocate(data))
+    }
+
+    pub fn should_collect(&self, size: usize) -> bool {
+        self.bytes_allocated + size > self.next_gc || !self.fits(size)
+    }
+
+    pub fn fits(&self, size: usize) -> bool {
+        self.max_bytes.is_none_or(|max| self.bytes_allocated + size <= max)
+    }
+
     pub fn free(&mut self, id: usize) {
-        self.values.remove(&id);
+        if let Some(val) = self.values.remove(&id) {
+            self.bytes_allocated -= val.size;
+        }
+    }
+
+    pub fn update<T>(&mut self, id: &usize, update: impl FnOnce(&mut HeapData) -> T) -> Option<T> {
+        let val = self.values.get_mut(id)?;
+        let result = update(&mut val.data);
+        let size = val.data.size();
+        self.bytes_allocated = self.bytes_allocated - val.size + size;
+        val.size = size;
+        Some(result)
+    }
+
+    pub fn collect_garbage(&mut self, roots: Vec<usize>) {
+        let mut gray = roots;
+        while let Some(id) = gray.pop() {
+            let Some(val) = self.values.get_mut(&id) else {
+                continue;
+            };
+            if val.marked {
+                continue;
+            }
+            val.marked = true;
+            let children = match &val.data {
+                HeapData::ObjFunction(function) => &function.chunk.constants,
+                HeapData::List(values) => values,
+                HeapData::Map(map) => {
+                    for (key, value) in map.entries() {
+                        gray.extend([key, value].into_iter().filter_map(|value| match value {
+                            Value::Obj(id) => Some(*id),
+                            _ => None,
+                        }));
+                    }
+                    continue;
+                },
+                _ => continue,
+            };
+            gray.extend(children.iter().filter_map(|value| match value {
+                Value::Obj(id) => Some(*id),
+                _ => None,
+            }));
+        }
+
+        let garbage: Vec<usize> = self.values.iter()
+            .filter(|(_, val)| !val.is_marked())
+            .map(|(id, _)| *id)
+            .collect();
+        for id in garbage {
+            self.free(id);
+        }
+        for val in self.values.values_mut() {
+            val.marked = false;
+        }
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(INITIAL_NEXT_GC);
     }
 
     pub fn get(&self, id: &usize) -> Option<&HeapData> {
@@ -37,20 +136,83 @@ impl Heap {
         }
     }
 
+    pub fn format_value(&self, value: &Value, mode: FormatMode) -> String {
+        self.format_nested(value, mode, &mut Vec::new())
+    }
+
+    fn format_nested(&self, value: &Value, mode: FormatMode, enclosing: &mut Vec<usize>) -> String {
+        match value {
+            Value::Number(num) => format!("{}", num),
+            Value::Nil => "nil".to_string(),
+            Value::Bool(b) => format!("{}", b),
+            Value::Obj(id) => match self.get(id) {
+                Some(HeapData::String(string)) => match mode {
+                    FormatMode::Display => string.clone(),
+                    FormatMode::Repr => format!("{:?}", string),
+                },
+                Some(HeapData::ObjFunction(function)) => format!("<fn {}>", function.name),
+                Some(HeapData::Native(native)) => format!("<native fn {}>", native.name),
+                Some(HeapData::List(_)) if enclosing.contains(id) => "[...]".to_string(),
+                Some(HeapData::List(values)) => {
+                    enclosing.push(*id);
+                    let elements: Vec<String> = values.iter()
+                        .map(|value| self.format_nested(value, FormatMode::Repr, enclosing))
+                        .collect();
+                    enclosing.pop();
+                    format!("[{}]", elements.join(", "))
+                },
+                Some(HeapData::Range(range)) => format!("range({}, {}, {})", range.start, range.end, range.step),
+                Some(HeapData::Map(_)) if enclosing.contains(id) => "{...}".to_string(),
+                Some(HeapData::Map(map)) => {
+                    enclosing.push(*id);
+                    let entries: Vec<String> = map.entries().iter()
+                        .map(|(key, value)| format!(
+                            "{}: {}",
+                            self.format_nested(key, FormatMode::Repr, enclosing),
+                            self.format_nested(value, FormatMode::Repr, enclosing),
+                        ))
+                        .collect();
+                    enclosing.pop();
+                    format!("{{{}}}", entries.join(", "))
+                },
+                None => "<freed object>".to_string(),
+            },
+        }
+    }
+
+    pub fn map_key(&self, value: &Value) -> Result<MapKey, String> {
+        match value {
+            Value::Nil => Ok(MapKey::Nil),
+            Value::Bool(b) => Ok(MapKey::Bool(*b)),
+            Value::Number(num) if num.is_nan() => Err("Map keys can't be NaN.".to_string()),
+            // -0 == 0, so both have to land on the same entry.
+            Value::Number(num) if *num == 0.0 => Ok(MapKey::Number(0f64.to_bits())),
+            Value::Number(num) => Ok(MapKey::Number(num.to_bits())),
+            Value::Obj(id) => match self.get(id) {
+                Some(HeapData::String(string)) => Ok(MapKey::String(string.clone())),
+                _ => Ok(MapKey::Obj(*id)),
+            },
+        }
+    }
+
     pub fn get_all(&self) -> &HashMap<usize, HeapVal> {
         &self.values
     }
 
-    pub fn mark(&mut self, id: usize) {
-        if let Some(val) = self.values.get_mut(&id) {
-            val.marked = true;
-        }
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    pub fn next_gc(&self) -> usize {
+        self.next_gc
     }
+
 }
 
 #[derive(Debug)]
 pub struct HeapVal {
     marked: bool,
+    size: usize,
     data: HeapData
 }
 
@@ -58,6 +220,7 @@ impl HeapVal {
     pub fn new(data: HeapData) -> HeapVal {
         HeapVal {
             marked: false,
+            size: data.size(),
             data
         }
     }
@@ -65,16 +228,130 @@ impl HeapVal {
     pub fn is_marked(&self) -> bool {
         self.marked
     }
+
+    pub fn data(&self) -> &HeapData {
+        &self.data
+    }
 }
 
 #[derive(Debug)]
 pub enum HeapData {
     String(String),
+    // Nothing compiles to a function object until the language has user-defined functions.
+    #[allow(dead_code)]
     ObjFunction(ObjFunction),
+    Native(ObjNative),
+    List(Vec<Value>),
+    Map(ObjMap),
+    Range(ObjRange),
+}
+
+#[derive(Debug, Default)]
+pub struct ObjMap {
+    entries: Vec<(Value, Value)>,
+    index: HashMap<MapKey, usize>,
+    read_only: bool,
+}
+
+impl ObjMap {
+    pub fn new() -> ObjMap {
+        ObjMap::default()
+    }
+
+    // Module namespaces are frozen so importers can't rewrite a cached module's variables.
+    pub fn freeze(&mut self) {
+        self.read_only = true;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn get(&self, key: &MapKey) -> Option<&Value> {
+        self.index.get(key).map(|idx| &self.entries[*idx].1)
+    }
+
+    pub fn contains(&self, key: &MapKey) -> bool {
+        self.index.contains_key(key)
+    }
+
+    pub fn insert_growth(&self, key: &MapKey) -> usize {
+        if self.contains(key) {
+            return 0;
+        }
+        let capacity = self.entries.capacity();
+        let key_string = match key {
+            MapKey::String(string) => string.capacity(),
+            _ => 0,
+        };
+        (grown_capacity(self.entries.len(), capacity) - capacity) * ObjMap::ENTRY_SIZE + key_string
+    }
+
+    pub fn insert(&mut self, key: MapKey, key_value: Value, value: Value) {
+        match self.index.get(&key) {
+            Some(idx) => self.entries[*idx].1 = value,
+            None => {
+                let capacity = grown_capacity(self.entries.len(), self.entries.capacity());
+                self.entries.reserve_exact(capacity - self.entries.len());
+                self.index.insert(key, self.entries.len());
+                self.entries.push((key_value, value));
+            },
+        }
+    }
+
+    pub fn remove(&mut self, key: &MapKey) -> Option<Value> {
+        let removed = self.index.remove(key)?;
+        for idx in self.index.values_mut() {
+            if *idx > removed {
+                *idx -= 1;
+            }
+        }
+        Some(self.entries.remove(removed).1)
+    }
+
+    pub fn entries(&self) -> &[(Value, Value)] {
+        &self.entries
+    }
+
+    // The index is charged per entry slot rather than by its own capacity, which the hash map
+    // grows on its own schedule.
+    const ENTRY_SIZE: usize = size_of::<(Value, Value)>() + size_of::<(MapKey, usize)>();
+
+    fn size(&self) -> usize {
+        let key_strings: usize = self.index.keys().map(|key| match key {
+            MapKey::String(string) => string.capacity(),
+            _ => 0,
+        }).sum();
+        self.entries.capacity() * ObjMap::ENTRY_SIZE + key_strings
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjRange {
+    pub start: f64,
+    pub end: f64,
+    pub step: f64,
+}
+
+impl ObjRange {
+    pub fn get(&self, index: usize) -> Option<f64> {
+        let value = self.start + index as f64 * self.step;
+        let in_range = if self.step > 0.0 { value < self.end } else { value > self.end };
+        in_range.then_some(value)
+    }
+}
+
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, String>;
+
+#[derive(Debug)]
+pub struct ObjNative {
+    pub name: String,
+    pub function: NativeFn,
 }
 
 #[derive(Debug)]
 pub struct ObjFunction {
+    #[allow(dead_code)]
     pub arity: usize,
     pub chunk: Chunk,
     pub name: String,
@@ -89,6 +366,7 @@ impl ObjFunction {
         }
     }
 
+    #[allow(dead_code)]
     pub fn with_name(name: String) -> ObjFunction {
         ObjFunction {
             arity: 0,
@@ -101,6 +379,7 @@ impl ObjFunction {
         &self.chunk
     }
 
+    #[allow(dead_code)]
     pub fn chunk_mut(&mut self) -> &mut Chunk {
         &mut self.chunk
     }
@@ -113,19 +392,47 @@ impl ObjFunction {
         &self.name
     }
 
+    #[allow(dead_code)]
     pub fn set_name(&mut self, name: String) {
         self.name = name;
     }
 }
 
 impl HeapData {
-    pub fn as_string(&self) -> &String {
-        if let HeapData::String(s) = self {
-            s
-        } else if let HeapData::ObjFunction(f) = self {
-            &f.name
-        } else {
-            panic!("Expected string, got something else")
+    pub fn size(&self) -> usize {
+        let data = match self {
+            HeapData::String(string) => string.capacity(),
+            HeapData::ObjFunction(function) => {
+                function.name.capacity()
+                    + function.chunk.code.capacity() * size_of::<(Op, Line)>()
+                    + function.chunk.constants.capacity() * size_of::<Value>()
+            },
+            HeapData::Native(native) => native.name.capacity(),
+            HeapData::List(values) => values.capacity() * size_of::<Value>(),
+            HeapData::Map(map) => map.size(),
+            HeapData::Range(_) => 0,
+        };
+        size_of::<HeapVal>() + data
+    }
+
+    pub fn as_string(&self) -> Option<&String> {
+        match self {
+            HeapData::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&Vec<Value>> {
+        match self {
+            HeapData::List(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&ObjMap> {
+        match self {
+            HeapData::Map(map) => Some(map),
+            _ => None,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,65 @@
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+// Outputs must be Send so a configured VM can be moved to another thread.
+pub trait Output: Send {
+    fn write(&mut self, text: &str);
+}
+
+pub struct StdoutOutput;
+
+impl Output for StdoutOutput {
+    fn write(&mut self, text: &str) {
+        let mut stdout = io::stdout();
+        // Like the trace, a closed stdout shouldn't abort the script.
+        let _ = stdout.write_all(text.as_bytes());
+        let _ = stdout.flush();
+    }
+}
+
+// Collects everything written, for embedders that want a script's output as a string. The
+// binary itself only writes to stdout, so outside the tests nothing here is used.
+#[derive(Clone, Default)]
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct BufferOutput {
+    buffer: Arc<Mutex<String>>,
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl BufferOutput {
+    pub fn new() -> BufferOutput {
+        BufferOutput::default()
+    }
+
+    pub fn contents(&self) -> String {
+        self.buffer.lock().unwrap().clone()
+    }
+
+    pub fn take(&self) -> String {
+        std::mem::take(&mut *self.buffer.lock().unwrap())
+    }
+}
+
+impl Output for BufferOutput {
+    fn write(&mut self, text: &str) {
+        self.buffer.lock().unwrap().push_str(text);
+    }
+}
+
+pub struct CallbackOutput {
+    callback: Box<dyn FnMut(&str) + Send>,
+}
+
+impl CallbackOutput {
+    pub fn new(callback: impl FnMut(&str) + Send + 'static) -> CallbackOutput {
+        CallbackOutput {
+            callback: Box::new(callback),
+        }
+    }
+}
+
+impl Output for CallbackOutput {
+    fn write(&mut self, text: &str) {
+        (self.callback)(text);
+    }
+}
@@ -0,0 +1,406 @@
+use crossterm::{
+    event::{self, Event, KeyCode, KeyModifiers},
+    terminal::{enable_raw_mode, disable_raw_mode},
+    cursor,
+    ExecutableCommand,
+};
+
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::debug::{self, TextRenderer};
+use crate::highlight;
+use crate::output::{CallbackOutput, Output};
+use crate::object::HeapData;
+use crate::scanner;
+use crate::value::{FormatMode, Value};
+use crate::vm::{self, InterpretResult};
+
+struct MetaCommand {
+    name: &'static str,
+    args: &'static str,
+    help: &'static str,
+}
+
+const COMMANDS: &[MetaCommand] = &[
+    MetaCommand { name: "help", args: "", help: "Show this list of commands" },
+    MetaCommand { name: "exit", args: "", help: "Leave the console" },
+    MetaCommand { name: "load", args: "<file>", help: "Run a lox script in the current session" },
+    MetaCommand { name: "reset", args: "", help: "Start over with a fresh VM" },
+    MetaCommand { name: "globals", args: "", help: "List the defined global variables" },
+    MetaCommand { name: "dis", args: "[function]", help: "Disassemble a function, or the last input" },
+    MetaCommand { name: "heap", args: "", help: "Show heap statistics" },
+    MetaCommand { name: "time", args: "<expr>", help: "Evaluate an expression and report how long it took" },
+    MetaCommand { name: "type", args: "<expr>", help: "Show the type of an expression" },
+    MetaCommand { name: "stack", args: "", help: "Show the stack while running further input" },
+    MetaCommand { name: "nostack", args: "", help: "Stop showing the stack" },
+];
+
+struct RawModeWriter;
+
+impl Write for RawModeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf).replace('\n', "\r\n");
+        io::stdout().write_all(text.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+fn raw_mode_output() -> Box<dyn Output> {
+    Box::new(CallbackOutput::new(|text| {
+        print!("{}", text.replace('\n', "\r\n"));
+    }))
+}
+
+pub fn repl(vm: &mut vm::VM) {
+    vm.init_vm();
+    vm.trace.out = Box::new(RawModeWriter);
+    vm.output = raw_mode_output();
+
+    let mut input_history: Vec<String> = Vec::new();
+    let mut current_input: String = String::new();
+    let mut cursor_pos: usize = 0;
+    let mut history_index: Option<usize> = None;
+    let colored = use_color();
+
+    enable_raw_mode().unwrap();
+    let mut stdout = io::stdout();
+    stdout.execute(crossterm::terminal::Clear(crossterm::terminal::ClearType::All)).unwrap();
+
+    print!("Welcome to Lox!\r\n");
+    print!("Type `:help` for a list of commands or `:exit` to exit the console.\r\n");
+    print!("> ");
+
+    loop {
+        io::stdout().flush().unwrap();        
+        if event::poll(std::time::Duration::from_millis(100)).unwrap() {
+            if let Event::Key(key_event) = event::read().unwrap() {
+                match key_event.code {
+                    KeyCode::Char(c) => {
+                        current_input.insert(byte_index(&current_input, cursor_pos), c);
+                        cursor_pos += 1;
+                    },
+                    KeyCode::Enter if !current_input.is_empty() => {
+                        redraw(&current_input, usize::MAX, colored);
+                        print!("\r\n");
+                        input_history.push(current_input.clone());
+                        let keep_running = interruptible(vm, |vm| {
+                            if let Some(command) = current_input.strip_prefix(':') {
+                                run_command(vm, command)
+                            } else {
+                                report(vm.interpret(current_input.clone()));
+                                true
+                            }
+                        });
+                        if !keep_running {
+                            break;
+                        }
+                        print!("\r\n");
+                        clear_line();
+                        print!("> ");
+                        current_input.clear();
+                        cursor_pos = 0;
+                        history_index = None;
+                        continue;
+                    },
+                    KeyCode::Tab => {
+                        let before_cursor = current_input[..byte_index(&current_input, cursor_pos)].to_string();
+                        let word_start = before_cursor
+                            .rfind(|c: char| !(c.is_alphanumeric() || c == '_' || c == ':'))
+                            .map_or(0, |idx| idx + 1);
+                        let word = before_cursor[word_start..].to_string();
+                        let candidates = complete(vm, &word, word_start == 0);
+                        let completion = if candidates.len() == 1 {
+                            candidates[0][word.len()..].to_string()
+                        } else if !candidates.is_empty() {
+                            let prefix = common_prefix(&candidates);
+                            if prefix.len() == word.len() {
+                                print!("\r\n{}\r\n", candidates.join("  "));
+                            }
+                            prefix[word.len()..].to_string()
+                        } else {
+                            String::new()
+                        };
+                        current_input.insert_str(before_cursor.len(), &completion);
+                        cursor_pos += completion.chars().count();
+                    },
+                    KeyCode::Backspace if cursor_pos > 0 => {
+                        cursor_pos -= 1;
+                        current_input.remove(byte_index(&current_input, cursor_pos));
+                    },
+                    KeyCode::Delete if cursor_pos < current_input.chars().count() => {
+                        current_input.remove(byte_index(&current_input, cursor_pos));
+                    },
+                    KeyCode::Up => {
+                        if let Some(idx) = history_index {
+                            if idx > 0 {
+                                history_index = Some(idx - 1);
+                            }
+                        } else if !input_history.is_empty() {
+                            history_index = Some(input_history.len() - 1);
+                        }
+                        if let Some(idx) = history_index {
+                            current_input = input_history[idx].clone();
+                            cursor_pos = current_input.chars().count();
+                        }
+                    },
+                    KeyCode::Down => {
+                        if let Some(idx) = history_index {
+                            if idx < input_history.len() - 1 {
+                                history_index = Some(idx + 1);
+                                current_input = input_history[idx + 1].clone();
+                            } else {
+                                history_index = None;
+                                current_input.clear();
+                            }
+                            cursor_pos = current_input.chars().count();
+                        }
+                    },
+                    KeyCode::Left if cursor_pos > 0 => {
+                        cursor_pos -= 1;
+                    },
+                    KeyCode::Right if cursor_pos < current_input.chars().count() => {
+                        cursor_pos += 1;
+                    },
+                    KeyCode::Home => {
+                        cursor_pos = 0;
+                    },
+                    KeyCode::End => {
+                        cursor_pos = current_input.chars().count();
+                    },
+                    _ => continue,
+                }
+                redraw(&current_input, cursor_pos, colored);
+            }
+        }
+    }
+    disable_raw_mode().unwrap();
+    println!();
+    println!("Goodbye!");
+}
+
+fn run_command(vm: &mut vm::VM, input: &str) -> bool {
+    let (name, argument) = match input.split_once(char::is_whitespace) {
+        Some((name, argument)) => (name, argument.trim()),
+        None => (input, ""),
+    };
+
+    match name {
+        "help" => {
+            for command in COMMANDS {
+                let usage = format!(":{} {}", command.name, command.args);
+                print!("  {:<20} {}\r\n", usage, command.help);
+            }
+        },
+        "exit" => {
+            return false;
+        },
+        "load" => {
+            if argument.is_empty() {
+                print!("Usage: :load <file>\r\n");
+            } else {
+                match fs::read_to_string(argument) {
+                    Ok(source) => {
+                        report(vm.interpret(source));
+                    },
+                    Err(err) => print!("Error reading file: {}\r\n", err),
+                }
+            }
+        },
+        "reset" => {
+            vm.reset();
+            print!("Started a fresh VM.\r\n");
+        },
+        "globals" => {
+            let mut names: Vec<&String> = vm.globals.keys().collect();
+            names.sort();
+            for name in names {
+                let global = &vm.globals[name];
+                let keyword = if global.constant { "const " } else { "" };
+                print!("{}{} = {}\r\n", keyword, name, vm.format_value(&global.value, FormatMode::Repr));
+            }
+        },
+        "dis" => {
+            if argument.is_empty() {
+                let listing = debug::dissassemble_chunk(&vm.chunk, &vm.heap, "input", &TextRenderer);
+                print!("{}", listing.replace('\n', "\r\n"));
+            } else {
+                let function = match vm.globals.get(argument).map(|global| &global.value) {
+                    Some(Value::Obj(id)) => match vm.heap.get(id) {
+                        Some(HeapData::ObjFunction(function)) => Some(function.chunk().clone()),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                match function {
+                    Some(chunk) => {
+                        let listing = debug::dissassemble_chunk(&chunk, &vm.heap, argument, &TextRenderer);
+                        print!("{}", listing.replace('\n', "\r\n"));
+                    },
+                    None => print!("'{}' is not a function.\r\n", argument),
+                }
+            }
+        },
+        "heap" => {
+            let mut strings = 0;
+            let mut functions = 0;
+            let mut natives = 0;
+            let mut lists = 0;
+            let mut maps = 0;
+            let mut ranges = 0;
+            for value in vm.heap.get_all().values() {
+                match value.data() {
+                    HeapData::String(_) => strings += 1,
+                    HeapData::ObjFunction(_) => functions += 1,
+                    HeapData::Native(_) => natives += 1,
+                    HeapData::List(_) => lists += 1,
+                    HeapData::Map(_) => maps += 1,
+                    HeapData::Range(_) => ranges += 1,
+                }
+            }
+            print!("objects:   {}\r\n", strings + functions + natives + lists + maps + ranges);
+            print!("strings:   {}\r\n", strings);
+            print!("functions: {}\r\n", functions);
+            print!("natives:   {}\r\n", natives);
+            print!("lists:     {}\r\n", lists);
+            print!("maps:      {}\r\n", maps);
+            print!("ranges:    {}\r\n", ranges);
+            print!("allocated: {} bytes\r\n", vm.heap.bytes_allocated());
+            print!("next gc:   {} bytes\r\n", vm.heap.next_gc());
+        },
+        "time" | "type" if argument.is_empty() => {
+            print!("Usage: :{} <expr>\r\n", name);
+        },
+        "time" => {
+            let start = Instant::now();
+            let result = vm.evaluate(argument.to_string());
+            let elapsed = start.elapsed();
+            match result {
+                Ok(value) => print!("{}\r\n", vm.format_value(&value, FormatMode::Repr)),
+                Err(result) => report(result),
+            }
+            print!("took {:?}\r\n", elapsed);
+        },
+        "type" => {
+            match vm.evaluate(argument.to_string()) {
+                Ok(value) => print!("{}\r\n", type_name(vm, &value)),
+                Err(result) => report(result),
+            }
+        },
+        "stack" => {
+            vm.trace.enabled = true;
+            print!("Further commands will show the stack...\r\n");
+        },
+        "nostack" => {
+            vm.trace.enabled = false;
+            print!("Further commands will not show the stack...\r\n");
+        },
+        _ => {
+            print!("Unknown command ':{}'. Type `:help` for a list of commands.\r\n", name);
+        },
+    }
+    true
+}
+
+fn interruptible<T>(vm: &mut vm::VM, run: impl FnOnce(&mut vm::VM) -> T) -> T {
+    // Raw mode swallows SIGINT, so Ctrl-C has to be read as a key while the VM runs.
+    let interrupt = vm.interrupt_handle();
+    let done = AtomicBool::new(false);
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            while !done.load(Ordering::Relaxed) {
+                if !event::poll(Duration::from_millis(50)).unwrap_or(false) {
+                    continue;
+                }
+                if let Ok(Event::Key(key_event)) = event::read() {
+                    if key_event.code == KeyCode::Char('c') && key_event.modifiers.contains(KeyModifiers::CONTROL) {
+                        interrupt.store(true, Ordering::Relaxed);
+                    }
+                }
+            }
+        });
+        let result = run(vm);
+        done.store(true, Ordering::Relaxed);
+        interrupt.store(false, Ordering::Relaxed);
+        result
+    })
+}
+
+fn report(result: InterpretResult) {
+    match result {
+        InterpretResult::Ok | InterpretResult::CompileError => (),
+        result => print!("{}\r\n", result.to_string().replace('\n', "\r\n")),
+    }
+}
+
+fn type_name(vm: &vm::VM, value: &Value) -> &'static str {
+    match value {
+        Value::Bool(_) => "bool",
+        Value::Nil => "nil",
+        Value::Number(_) => "number",
+        Value::Obj(id) => match vm.heap.get(id) {
+            Some(HeapData::String(_)) => "string",
+            Some(HeapData::ObjFunction(_)) => "function",
+            Some(HeapData::Native(_)) => "native",
+            Some(HeapData::List(_)) => "list",
+            Some(HeapData::Map(_)) => "map",
+            Some(HeapData::Range(_)) => "range",
+            None => "object",
+        },
+    }
+}
+
+fn use_color() -> bool {
+    io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+}
+
+fn redraw(input: &str, cursor_pos: usize, colored: bool) {
+    let line = if colored {
+        highlight::highlight(input, cursor_pos)
+    } else {
+        input.to_string()
+    };
+    print!("\r{}> {}", clear_line(), line);
+    let column = 2 + cursor_pos.min(input.chars().count());
+    io::stdout().execute(cursor::MoveToColumn(column as u16)).unwrap();
+}
+
+fn byte_index(input: &str, char_pos: usize) -> usize {
+    input.char_indices().nth(char_pos).map_or(input.len(), |(idx, _)| idx)
+}
+
+fn complete(vm: &vm::VM, word: &str, at_line_start: bool) -> Vec<String> {
+    let mut candidates: Vec<String> = if at_line_start && word.starts_with(':') {
+        COMMANDS.iter().map(|command| format!(":{}", command.name)).collect()
+    } else {
+        scanner::KEYWORDS.iter()
+            .map(|keyword| keyword.to_string())
+            .chain(vm.globals.keys().cloned())
+            .collect()
+    };
+    candidates.retain(|candidate| candidate.starts_with(word));
+    candidates.sort();
+    candidates.dedup();
+    candidates
+}
+
+fn common_prefix(candidates: &[String]) -> String {
+    let mut prefix = candidates[0].clone();
+    for candidate in candidates.iter().skip(1) {
+        while !candidate.starts_with(&prefix) {
+            prefix.pop();
+        }
+    }
+    prefix
+}
+
+fn clear_line() -> &'static str {
+    "\x1b[2K\x1b[1G"
+}
\ No newline at end of file
@@ -1,3 +1,9 @@
+pub const KEYWORDS: &[&str] = &[
+    "and", "break", "catch", "class", "const", "continue", "else", "false", "finally", "for",
+    "from", "fun", "if", "import", "in", "nil", "or", "print", "return", "super",
+    "this", "throw", "true", "try", "var", "while",
+];
+
 pub struct Scanner {
     start: usize,
     current: usize,
@@ -13,12 +19,12 @@ impl Scanner {
         }
     }
 
-    pub fn scan_token(&mut self, source: &String) -> Token {
+    pub fn scan_token(&mut self, source: &str) -> Token {
         self.skip_whitespace(source);
         self.start = self.current;
 
         if self.is_at_end(source) {
-            return self.make_token(TokenType::EOF);
+            return self.make_token(TokenType::Eof);
         }
 
         match self.advance(source) {
@@ -26,13 +32,73 @@ impl Scanner {
             ')' => return self.make_token(TokenType::RightParen),
             '{' => return self.make_token(TokenType::LeftBrace),
             '}' => return self.make_token(TokenType::RightBrace),
+            '[' => return self.make_token(TokenType::LeftBracket),
+            ']' => return self.make_token(TokenType::RightBracket),
             ';' => return self.make_token(TokenType::Semicolon),
+            ':' => return self.make_token(TokenType::Colon),
+            '?' => {
+                let m = self.match_next(source, '?');
+                return self.make_token(if m {
+                    TokenType::QuestionQuestion
+                } else {
+                    TokenType::Question
+                });
+            },
             ',' => return self.make_token(TokenType::Comma),
             '.' => return self.make_token(TokenType::Dot),
-            '-' => return self.make_token(TokenType::Minus),
-            '+' => return self.make_token(TokenType::Plus),
-            '/' => return self.make_token(TokenType::Slash),
-            '*' => return self.make_token(TokenType::Star),
+            '-' => {
+                if self.match_next(source, '-') {
+                    return self.make_token(TokenType::MinusMinus);
+                }
+                let m = self.match_next(source, '=');
+                return self.make_token(if m {
+                    TokenType::MinusEqual
+                } else {
+                    TokenType::Minus
+                });
+            },
+            '+' => {
+                if self.match_next(source, '+') {
+                    return self.make_token(TokenType::PlusPlus);
+                }
+                let m = self.match_next(source, '=');
+                return self.make_token(if m {
+                    TokenType::PlusEqual
+                } else {
+                    TokenType::Plus
+                });
+            },
+            '/' => {
+                let m = self.match_next(source, '=');
+                return self.make_token(if m {
+                    TokenType::SlashEqual
+                } else {
+                    TokenType::Slash
+                });
+            },
+            '*' => {
+                if self.match_next(source, '*') {
+                    return self.make_token(TokenType::StarStar);
+                }
+                let m = self.match_next(source, '=');
+                return self.make_token(if m {
+                    TokenType::StarEqual
+                } else {
+                    TokenType::Star
+                });
+            },
+            '%' => {
+                let m = self.match_next(source, '=');
+                return self.make_token(if m {
+                    TokenType::PercentEqual
+                } else {
+                    TokenType::Percent
+                });
+            },
+            '&' => return self.make_token(TokenType::Ampersand),
+            '|' => return self.make_token(TokenType::Pipe),
+            '^' => return self.make_token(TokenType::Caret),
+            '~' => return self.make_token(TokenType::Tilde),
             '!' => {
                 let m = self.match_next(source, '=');
                 return self.make_token(if m {
@@ -50,6 +116,9 @@ impl Scanner {
                 });
             },
             '<' => {
+                if self.match_next(source, '<') {
+                    return self.make_token(TokenType::LessLess);
+                }
                 let m = self.match_next(source, '=');
                 return self.make_token(if m {
                     TokenType::LessEqual
@@ -58,6 +127,9 @@ impl Scanner {
                 });
             },
             '>' => {
+                if self.match_next(source, '>') {
+                    return self.make_token(TokenType::GreaterGreater);
+                }
                 let m = self.match_next(source, '=');
                 return self.make_token(if m {
                     TokenType::GreaterEqual
@@ -72,18 +144,22 @@ impl Scanner {
                 return self.number_token(source);
             },
             'a'..='z' | 'A'..='Z' => {
-                while self.peek(source).is_alphanumeric() || ['_', '-'].contains(&self.peek(source)) {
+                while self.peek(source).is_alphanumeric() || self.peek(source) == '_' {
                     self.advance(source);
                 }
 
                 return self.make_token(self.identifier_type(source));
             },
-            c => println!("\r\n{}", c)
+            _ => ()
         }
     
         self.error_token("Unexpected character.")
     }
 
+    pub fn span(&self) -> (usize, usize) {
+        (self.start, self.current)
+    }
+
     fn make_token(&self, token_type: TokenType) -> Token {
         Token {
             token_type,
@@ -102,29 +178,58 @@ impl Scanner {
         }
     }
 
-    fn identifier_type(&self, source: &String) -> TokenType {
+    fn identifier_type(&self, source: &str) -> TokenType {
         match source.chars().nth(self.start).unwrap() {
             'a' => return self.check_keyword(source, 1, 2, "nd", TokenType::And),
-            'c' => return self.check_keyword(source, 1, 4, "lass", TokenType::Class),
+            'b' => return self.check_keyword(source, 1, 4, "reak", TokenType::Break),
+            'c' => {
+                match source.chars().nth(self.start + 1).unwrap_or('\0') {
+                    'a' => return self.check_keyword(source, 2, 3, "tch", TokenType::Catch),
+                    'l' => return self.check_keyword(source, 2, 3, "ass", TokenType::Class),
+                    'o' => match source.chars().nth(self.start + 3).unwrap_or('\0') {
+                        's' => return self.check_keyword(source, 2, 3, "nst", TokenType::Const),
+                        't' => return self.check_keyword(source, 2, 6, "ntinue", TokenType::Continue),
+                        _ => ()
+                    },
+                    _ => ()
+                }
+            },
             'e' => return self.check_keyword(source, 1, 3, "lse", TokenType::Else),
             'f' => {
-                match source.chars().nth(self.start + 1).unwrap() {
+                match source.chars().nth(self.start + 1).unwrap_or('\0') {
                     'a' => return self.check_keyword(source, 2, 3, "lse", TokenType::False),
+                    'i' => return self.check_keyword(source, 2, 5, "nally", TokenType::Finally),
                     'o' => return self.check_keyword(source, 2, 1, "r", TokenType::For),
+                    'r' => return self.check_keyword(source, 2, 2, "om", TokenType::From),
                     'u' => return self.check_keyword(source, 2, 1, "n", TokenType::Fun),
                     _ => ()
                 }
             },
-            'i' => return self.check_keyword(source, 1, 1, "f", TokenType::If),
+            'i' => {
+                match source.chars().nth(self.start + 1).unwrap_or('\0') {
+                    'f' => return self.check_keyword(source, 2, 0, "", TokenType::If),
+                    'm' => return self.check_keyword(source, 2, 4, "port", TokenType::Import),
+                    'n' => return self.check_keyword(source, 2, 0, "", TokenType::In),
+                    _ => ()
+                }
+            },
             'n' => return self.check_keyword(source, 1, 2, "il", TokenType::Nil),
             'o' => return self.check_keyword(source, 1, 1, "r", TokenType::Or),
             'p' => return self.check_keyword(source, 1, 4, "rint", TokenType::Print),
             'r' => return self.check_keyword(source, 1, 5, "eturn", TokenType::Return),
             's' => return self.check_keyword(source, 1, 4, "uper", TokenType::Super),
             't' => {
-                match source.chars().nth(self.start + 1).unwrap() {
-                    'h' => return self.check_keyword(source, 2, 2, "is", TokenType::This),
-                    'r' => return self.check_keyword(source, 2, 2, "ue", TokenType::True),
+                match source.chars().nth(self.start + 1).unwrap_or('\0') {
+                    'h' => match source.chars().nth(self.start + 2).unwrap_or('\0') {
+                        'i' => return self.check_keyword(source, 3, 1, "s", TokenType::This),
+                        'r' => return self.check_keyword(source, 3, 2, "ow", TokenType::Throw),
+                        _ => ()
+                    },
+                    'r' => match source.chars().nth(self.start + 2).unwrap_or('\0') {
+                        'u' => return self.check_keyword(source, 3, 1, "e", TokenType::True),
+                        'y' => return self.check_keyword(source, 3, 0, "", TokenType::Try),
+                        _ => ()
+                    },
                     _ => ()
                 }
             },
@@ -135,10 +240,13 @@ impl Scanner {
             _ => ()
         }
 
-        return TokenType::Identifier;
+        TokenType::Identifier
     }
 
-    fn check_keyword(&self, source: &String, start: usize, length: usize, rest: &str, token_type: TokenType) -> TokenType {
+    fn check_keyword(&self, source: &str, start: usize, length: usize, rest: &str, token_type: TokenType) -> TokenType {
+        if self.current - self.start != start + length {
+            return TokenType::Identifier;
+        }
         let some_chars = &source[self.start + start..self.start + start + length];
         if some_chars == rest {
             return token_type;
@@ -147,26 +255,26 @@ impl Scanner {
         TokenType::Identifier
     }
 
-    fn advance(&mut self, source: &String) -> char {
+    fn advance(&mut self, source: &str) -> char {
         self.current += 1;
         source.chars().nth(self.current - 1).unwrap()
     }
 
-    fn peek(&self, source: &String) -> char {
+    fn peek(&self, source: &str) -> char {
         if self.is_at_end(source) {
             return '\0';
         }
         source.chars().nth(self.current).unwrap()
     }
 
-    fn peek_next(&self, source: &String) -> char {
+    fn peek_next(&self, source: &str) -> char {
         if self.is_at_end(source) {
             return '\0';
         }
-        source.chars().nth(self.current + 1).unwrap()
+        source.chars().nth(self.current + 1).unwrap_or('\0')
     }
 
-    fn skip_whitespace(&mut self, source: &String) {
+    fn skip_whitespace(&mut self, source: &str) {
         loop {
             let c: char = self.peek(source);
             match c {
@@ -177,13 +285,9 @@ impl Scanner {
                     self.line += 1;
                     self.advance(source);
                 },
-                '/' => {
-                    if self.peek_next(source) == '/' {
-                        while self.peek(source) != '\n' && !self.is_at_end(source) {
-                            self.advance(source);
-                        }
-                    } else {
-                        break;
+                '/' if self.peek_next(source) == '/' => {
+                    while self.peek(source) != '\n' && !self.is_at_end(source) {
+                        self.advance(source);
                     }
                 },
                 _ => {
@@ -193,7 +297,7 @@ impl Scanner {
         }
     }
 
-    fn match_next(&mut self, source: &String, expected: char) -> bool {
+    fn match_next(&mut self, source: &str, expected: char) -> bool {
         if self.is_at_end(source) {
             return false;
         }
@@ -205,7 +309,7 @@ impl Scanner {
         true
     }
 
-    fn string_token(&mut self, source: &String) -> Token {
+    fn string_token(&mut self, source: &str) -> Token {
         while self.peek(source) != '"' && !self.is_at_end(source) {
             if self.peek(source) == '\n' {
                 self.line += 1;
@@ -221,14 +325,14 @@ impl Scanner {
         self.make_token(TokenType::String)
     }
 
-    fn number_token(&mut self, source: &String) -> Token {
-        while self.peek(source).is_digit(10) {
+    fn number_token(&mut self, source: &str) -> Token {
+        while self.peek(source).is_ascii_digit() {
             self.advance(source);
         }
 
-        if self.peek(source) == '.' && self.peek_next(source).is_digit(10) {
+        if self.peek(source) == '.' && self.peek_next(source).is_ascii_digit() {
             self.advance(source);
-            while self.peek(source).is_digit(10) {
+            while self.peek(source).is_ascii_digit() {
                 self.advance(source);
             }
         }
@@ -236,7 +340,7 @@ impl Scanner {
         self.make_token(TokenType::Number)
     }
 
-    fn is_at_end(&self, source: &String) -> bool {
+    fn is_at_end(&self, source: &str) -> bool {
         self.current >= source.len()
     }
 }
@@ -255,40 +359,70 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
+    MinusMinus,
+    MinusEqual,
     Plus,
+    PlusPlus,
+    PlusEqual,
     Semicolon,
+    Colon,
+    Question,
+    QuestionQuestion,
     Slash,
+    SlashEqual,
     Star,
+    StarStar,
+    StarEqual,
+    Percent,
+    PercentEqual,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
     Bang,
     BangEqual,
     Equal,
     EqualEqual,
     Greater,
     GreaterEqual,
+    GreaterGreater,
     Less,
     LessEqual,
+    LessLess,
     Identifier,
     String,
     Number,
     And,
+    Break,
+    Catch,
     Class,
+    Const,
+    Continue,
     Else,
     False,
+    Finally,
     For,
+    From,
     Fun,
     If,
+    Import,
+    In,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
     Error,
-    EOF,
+    Eof,
 }
\ No newline at end of file
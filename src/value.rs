@@ -1,7 +1,22 @@
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug)]
 pub enum Value {
     Bool(bool),
     Nil,
     Number(f64),
     Obj(usize),
-}
\ No newline at end of file
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Nil,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Obj(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FormatMode {
+    Display,
+    Repr,
+}
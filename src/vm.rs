@@ -1,5 +1,11 @@
-use std::any::{type_name, Any};
 use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::chunk::{
     Chunk,
@@ -8,41 +14,128 @@ use crate::chunk::{
 };
 use crate::debug::{
     dissassemble_instruction,
-    print_value,
+    TraceConfig,
 };
 use crate::value::{
+    FormatMode,
+    MapKey,
     Value,
     Value::*,
 };
 
 use crate::compile::Parser;
+use crate::native;
+use crate::output::{Output, StdoutOutput};
 
 use crate::object::{
     Heap,
     HeapData,
+    NativeFn,
+    ObjMap,
+    ObjNative,
 };
 
 pub struct VM {
     pub chunk: Chunk,
     pub ip: usize,
     pub stack: Vec<Value>,
-    pub globals: HashMap<String, Value>,
+    pub globals: HashMap<String, Global>,
     pub heap: Heap,
+    pub args: Vec<String>,
+    pub trace: TraceConfig,
+    pub output: Box<dyn Output>,
+    pub limits: Limits,
+    call_depth: usize,
+    interrupt: Arc<AtomicBool>,
+    thrown: Option<Value>,
+    pub script_path: Option<PathBuf>,
+    builtins: HashMap<String, Value>,
+    modules: HashMap<PathBuf, Value>,
+    importers: Vec<Importer>,
+}
+
+// The state of a script or module that is paused while a module it imports runs.
+struct Importer {
+    chunk: Chunk,
+    ip: usize,
+    stack: Vec<Value>,
+    globals: HashMap<String, Global>,
+    script_path: Option<PathBuf>,
+}
+
+pub struct Global {
+    pub value: Value,
+    pub constant: bool,
+}
+
+impl Global {
+    fn new(value: Value, constant: bool) -> Global {
+        Global { value, constant }
+    }
+}
+
+pub struct Limits {
+    pub max_stack: usize,
+    pub max_call_depth: usize,
+    pub max_instructions: Option<usize>,
+    pub timeout: Option<Duration>,
+}
+
+impl Limits {
+    pub fn new() -> Limits {
+        Limits {
+            max_stack: DEFAULT_MAX_CALL_DEPTH * STACK_MAX,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            max_instructions: None,
+            timeout: None,
+        }
+    }
 }
 
 const STACK_MAX: usize = 256;
-pub static mut DEBUG_TRACE_EXECUTION: bool = false;
+const DEFAULT_MAX_CALL_DEPTH: usize = 1024;
+const SCRIPT_NAME: &str = "script";
+const COLLAPSE_AFTER: usize = 3;
+const DEADLINE_CHECK_INTERVAL: usize = 1024;
+const LOX_PATH: &str = "LOX_PATH";
 
 macro_rules! binary_op {
+    ($vm:expr, fn $op:expr) => {
+        {
+            let b = $vm.pop();
+            let a = $vm.pop();
+            if let (Number(a), Number(b)) = (a, b) {
+                $vm.push(Number($op(a, b)));
+            } else {
+                return $vm.runtime_error("Operands must be numbers".to_string());
+            }
+        }
+    };
     ($vm:expr, $valType:path, $op:tt) => {
         {
-            if let Number(b) = $vm.pop() {
-                if let Number(a) = $vm.pop() {
-                    $vm.push($valType((a $op b)));
-                }
+            let b = $vm.pop();
+            let a = $vm.pop();
+            if let (Number(a), Number(b)) = (a, b) {
+                $vm.push($valType(a $op b));
             } else {
-                $vm.runtime_error(format!("Operands must be numbers"));
-                return InterpretResult::RuntimeError;
+                return $vm.runtime_error("Operands must be numbers".to_string());
+            }
+        }
+    };
+}
+
+macro_rules! integer_op {
+    ($vm:expr, $op:expr) => {
+        {
+            let b = $vm.pop();
+            let a = $vm.pop();
+            let (Some(a), Some(b)) = (to_integer(&a), to_integer(&b)) else {
+                return $vm.runtime_error("Operands must be integers".to_string());
+            };
+            let result: Result<i64, String> = $op(a, b);
+            match result {
+                Ok(result) => $vm.push(Number(result as f64)),
+                Err(message) => return $vm.runtime_error(message),
             }
         }
     };
@@ -51,12 +144,127 @@ macro_rules! binary_op {
 
 impl VM {
     pub fn new() -> VM {
-        VM {
+        let mut vm = VM {
             chunk: Chunk::new(),
             ip: 0,
             stack: Vec::with_capacity(STACK_MAX),
             globals: HashMap::new(),
             heap: Heap::new(),
+            args: Vec::new(),
+            trace: TraceConfig::new(),
+            output: Box::new(StdoutOutput),
+            limits: Limits::new(),
+            call_depth: 0,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            thrown: None,
+            script_path: None,
+            builtins: HashMap::new(),
+            modules: HashMap::new(),
+            importers: Vec::new(),
+        };
+        native::define_natives(&mut vm);
+        vm
+    }
+
+    // Starts over with fresh state but keeps everything configured from the command line.
+    pub fn reset(&mut self) {
+        let mut fresh = VM::new();
+        fresh.args = mem::take(&mut self.args);
+        fresh.trace = mem::replace(&mut self.trace, TraceConfig::new());
+        fresh.output = mem::replace(&mut self.output, Box::new(StdoutOutput));
+        fresh.limits = mem::replace(&mut self.limits, Limits::new());
+        fresh.heap.max_bytes = self.heap.max_bytes;
+        fresh.script_path = self.script_path.take();
+        fresh.interrupt = self.interrupt.clone();
+        *self = fresh;
+    }
+
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    fn stop(&mut self, result: InterpretResult) -> InterpretResult {
+        self.reset_stack();
+        result
+    }
+
+    pub fn define_native(&mut self, name: &str, function: NativeFn) {
+        let native = ObjNative {
+            name: name.to_string(),
+            function,
+        };
+        let heap_id = self.heap.try_allocate(HeapData::Native(native))
+            .expect("natives are defined before a memory cap is set");
+        self.globals.insert(name.to_string(), Global::new(Obj(heap_id), false));
+        self.builtins.insert(name.to_string(), Obj(heap_id));
+    }
+
+    pub fn allocate(&mut self, data: HeapData) -> Result<usize, String> {
+        if self.heap.should_collect(data.size()) {
+            self.collect_garbage();
+        }
+        self.heap.try_allocate(data)
+    }
+
+    pub fn reserve(&mut self, size: usize) -> Result<(), String> {
+        if self.heap.should_collect(size) {
+            self.collect_garbage();
+        }
+        if !self.heap.fits(size) {
+            return Err("Out of memory.".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn collect_garbage(&mut self) {
+        let suspended = self.importers.iter()
+            .flat_map(|importer| importer.stack.iter().chain(importer.globals.values().map(|global| &global.value)).chain(importer.chunk.constants.iter()));
+        let roots = self.stack.iter()
+            .chain(self.globals.values().map(|global| &global.value))
+            .chain(self.chunk.constants.iter())
+            .chain(self.thrown.iter())
+            .chain(self.builtins.values())
+            .chain(self.modules.values())
+            .chain(suspended)
+            .filter_map(|value| match value {
+                Obj(id) => Some(*id),
+                _ => None,
+            })
+            .collect();
+        self.heap.collect_garbage(roots);
+    }
+
+    fn call_value(&mut self, arg_count: usize) -> Result<(), String> {
+        let callee = self.peek(arg_count);
+        let native = match callee {
+            Obj(heap_id) => match self.heap.get(&heap_id) {
+                Some(HeapData::Native(native)) => Some(native.function),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        if self.call_depth >= self.limits.max_call_depth {
+            return Err("Stack overflow.".to_string());
+        }
+
+        match native {
+            Some(function) => {
+                let args_start = self.stack.len() - arg_count;
+                let args: Vec<Value> = self.stack[args_start..].to_vec();
+                self.call_depth += 1;
+                let result = function(self, &args);
+                self.call_depth -= 1;
+                match result {
+                    Ok(result) => {
+                        self.stack.truncate(args_start - 1);
+                        self.push(result);
+                        Ok(())
+                    },
+                    Err(message) => Err(message),
+                }
+            },
+            None => Err("Can only call functions and classes.".to_string()),
         }
     }
 
@@ -67,7 +275,14 @@ impl VM {
     }
 
     pub fn reset_stack(&mut self) {
+        if !self.importers.is_empty() {
+            let script = self.importers.swap_remove(0);
+            self.importers.clear();
+            self.resume_importer(script);
+        }
         self.stack.clear();
+        self.call_depth = 0;
+        self.thrown = None;
     }
 
     pub fn push(&mut self, value: Value) {
@@ -82,70 +297,179 @@ impl VM {
         self.stack[self.stack.len() - 1 - distance].clone()
     }
 
-    pub fn equal(&self, a: Value, b: Value) -> bool {
-        if a.type_id() != b.type_id() {
-            return false;
-        } else {
-            match a {
-                Bool(a) => {
-                    if let Bool(b) = b {
-                        a == b
-                    } else {
-                        false
-                    }
-                },
-                Number(a) => {
-                    if let Number(b) = b {
-                        a == b
-                    } else {
-                        false
-                    }
-                },
-                Value::Nil => {
-                    return true;
-                },
-                Obj(obj_id_a) => {
-                    if let Obj(obj_id_b) = b {
-                        let obj_a = self.heap.get(&obj_id_a).unwrap();
-                        let obj_b = self.heap.get(&obj_id_b).unwrap();
-                        match obj_a {
-                            HeapData::String(a) => {
-                                if let HeapData::String(b) = obj_b {
-                                    a == b
-                                } else {
-                                    false
-                                }
-                            }
-                            _ => {
-                                false
-                            }
-                        }
-                    } else {
-                        false
-                    }
+    pub fn format_value(&self, value: &Value, mode: FormatMode) -> String {
+        self.heap.format_value(value, mode)
+    }
+
+    pub fn as_list(&self, value: &Value) -> Option<&Vec<Value>> {
+        match value {
+            Obj(heap_id) => self.heap.get(heap_id).and_then(HeapData::as_list),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self, value: &Value) -> Option<&ObjMap> {
+        match value {
+            Obj(heap_id) => self.heap.get(heap_id).and_then(HeapData::as_map),
+            _ => None,
+        }
+    }
+
+    fn is_iterable(&self, value: &Value) -> bool {
+        match value {
+            Obj(heap_id) => matches!(
+                self.heap.get(heap_id),
+                Some(HeapData::List(_) | HeapData::Map(_) | HeapData::String(_) | HeapData::Range(_))
+            ),
+            _ => false,
+        }
+    }
+
+    fn iter_next(&mut self, slot: usize) -> Result<Option<Value>, String> {
+        let Obj(heap_id) = self.stack[slot] else {
+            return Ok(None);
+        };
+        let Number(position) = self.stack[slot + 1] else {
+            return Ok(None);
+        };
+        let position = position as usize;
+
+        // Strings step by byte offset so that each step is O(1); everything else steps by index.
+        let (value, next) = match self.heap.get(&heap_id) {
+            Some(HeapData::List(values)) => match values.get(position) {
+                Some(value) => (value.clone(), position + 1),
+                None => return Ok(None),
+            },
+            Some(HeapData::Map(map)) => match map.entries().get(position) {
+                Some((key, _)) => (key.clone(), position + 1),
+                None => return Ok(None),
+            },
+            Some(HeapData::Range(range)) => match range.get(position) {
+                Some(value) => (Number(value), position + 1),
+                None => return Ok(None),
+            },
+            Some(HeapData::String(string)) => match string[position..].chars().next() {
+                Some(c) => {
+                    let value = Obj(self.allocate(HeapData::String(c.to_string()))?);
+                    (value, position + c.len_utf8())
                 },
-                _ => {
-                    return false;
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        self.stack[slot + 1] = Number(next as f64);
+        Ok(Some(value))
+    }
+
+    fn index_get(&self, target: &Value, index: &Value) -> Result<Value, String> {
+        if let Some(values) = self.as_list(target) {
+            return Ok(values[list_index(index, values.len())?].clone());
+        }
+        if let Some(map) = self.as_map(target) {
+            let key = self.heap.map_key(index)?;
+            return Ok(map.get(&key).cloned().unwrap_or(Value::Nil));
+        }
+        Err("Can only index lists and maps.".to_string())
+    }
+
+    fn index_set(&mut self, target: &Value, index: &Value, value: Value) -> Result<(), String> {
+        let Obj(heap_id) = target else {
+            return Err("Can only index lists and maps.".to_string());
+        };
+        if let Some(values) = self.as_list(target) {
+            let index = list_index(index, values.len())?;
+            self.heap.update(heap_id, |data| {
+                if let HeapData::List(values) = data {
+                    values[index] = value;
                 }
+            });
+            return Ok(());
+        }
+        if let Some(map) = self.as_map(target) {
+            if map.is_read_only() {
+                return Err("Module namespaces are read-only.".to_string());
             }
+            let key = self.heap.map_key(index)?;
+            let growth = self.as_map(target).map_or(0, |map| map.insert_growth(&key));
+            self.reserve(growth)?;
+            self.heap.update(heap_id, |data| {
+                if let HeapData::Map(map) = data {
+                    map.insert(key, index.clone(), value);
+                }
+            });
+            return Ok(());
         }
+        Err("Can only index lists and maps.".to_string())
     }
 
-    pub fn interpret(&mut self, source: String) -> InterpretResult {
+    fn is_concatenable(&self, value: &Value) -> bool {
+        match value {
+            Number(_) => true,
+            Obj(heap_id) => self.heap.get(heap_id).and_then(HeapData::as_string).is_some(),
+            _ => false,
+        }
+    }
+
+    pub fn equal(&self, a: &Value, b: &Value) -> bool {
+        match (a, b) {
+            (Bool(a), Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Number(a), Number(b)) => a == b,
+            (Obj(a), Obj(b)) => match (self.heap.get(a), self.heap.get(b)) {
+                (Some(HeapData::String(a)), Some(HeapData::String(b))) => a == b,
+                _ => a == b,
+            },
+            _ => false,
+        }
+    }
+
+    pub fn is_falsey(&self, value: &Value) -> bool {
+        matches!(value, Value::Nil | Bool(false))
+    }
+
+    pub fn compile(&mut self, source: String) -> Option<Chunk> {
+        if self.heap.should_collect(source.len()) {
+            self.collect_garbage();
+        }
         let mut parser = Parser::new();
-        
+
         let mut chunk: Chunk = Chunk::new();
 
         if !parser.compile(source, &mut chunk, &mut self.heap) {
+            return None;
+        }
+        Some(chunk)
+    }
+
+    pub fn interpret(&mut self, source: String) -> InterpretResult {
+        let Some(chunk) = self.compile(source) else {
             return InterpretResult::CompileError;
+        };
+
+        self.chunk = chunk;
+        self.ip = 0;
+        self.reset_stack();
+
+        self.run()
+    }
+
+    pub fn evaluate(&mut self, source: String) -> Result<Value, InterpretResult> {
+        let mut parser = Parser::new();
+
+        let mut chunk: Chunk = Chunk::new();
+
+        if !parser.compile_expression(source, &mut chunk, &mut self.heap) {
+            return Err(InterpretResult::CompileError);
         }
 
         self.chunk = chunk;
         self.ip = 0;
-        self.stack.clear();
+        self.reset_stack();
 
-        let result = self.run();
-        result
+        match self.run() {
+            InterpretResult::Ok => Ok(self.pop()),
+            result => Err(result),
+        }
     }
 
     fn read_byte(&mut self) -> Op {
@@ -154,16 +478,231 @@ impl VM {
         operation
     }
 
-    fn runtime_error(&mut self, format: String) {
-        println!("{}", format);
-        let instruction = self.chunk.code[self.ip - 1].clone();
-        println!("{:?}", instruction.0);
-        println!("[line {:?}] in script", instruction.1);
-        self.stack.clear();
-    } 
+    fn runtime_error(&mut self, message: String) -> InterpretResult {
+        let trace = vec![TraceFrame {
+            function: self.frame_name(),
+            line: self.current_line(),
+        }];
+        InterpretResult::RuntimeError(RuntimeError { message, trace })
+    }
+
+    fn current_line(&self) -> usize {
+        self.chunk.code.get(self.ip.saturating_sub(1)).map_or(0, |(_, line)| line.value)
+    }
+
+    fn frame_name(&self) -> String {
+        match &self.script_path {
+            Some(path) if !self.importers.is_empty() => path.display().to_string(),
+            _ => SCRIPT_NAME.to_string(),
+        }
+    }
+
+    fn import(&mut self, path: &str) -> Result<(), String> {
+        let path = self.resolve_module(path)?;
+        if let Some(module) = self.modules.get(&path) {
+            self.push(module.clone());
+            return Ok(());
+        }
+        let mut importing: Vec<&PathBuf> = self.importers.iter()
+            .filter_map(|importer| importer.script_path.as_ref())
+            .chain(self.script_path.iter())
+            .collect();
+        if let Some(start) = importing.iter().position(|importing| **importing == path) {
+            importing.push(&path);
+            let cycle: Vec<String> = importing[start..].iter().map(|path| path.display().to_string()).collect();
+            return Err(format!("Import cycle: {}.", cycle.join(" -> ")));
+        }
+
+        let source = fs::read_to_string(&path)
+            .map_err(|err| format!("Could not read module '{}': {}.", path.display(), err))?;
+        let chunk = self.compile(source)
+            .ok_or_else(|| format!("Could not compile module '{}'.", path.display()))?;
+        let globals = self.builtins.iter()
+            .map(|(name, value)| (name.clone(), Global::new(value.clone(), false)))
+            .collect();
+        self.importers.push(Importer {
+            chunk: mem::replace(&mut self.chunk, chunk),
+            ip: self.ip,
+            stack: mem::take(&mut self.stack),
+            globals: mem::replace(&mut self.globals, globals),
+            script_path: self.script_path.replace(path),
+        });
+        self.ip = 0;
+        Ok(())
+    }
+
+    // Module paths are relative to the importing file, then to each directory in LOX_PATH.
+    fn resolve_module(&self, path: &str) -> Result<PathBuf, String> {
+        let base = self.script_path.as_deref()
+            .and_then(Path::parent)
+            .map_or_else(PathBuf::new, Path::to_path_buf);
+        let search_path = env::var_os(LOX_PATH)
+            .map(|paths| env::split_paths(&paths).collect::<Vec<_>>())
+            .unwrap_or_default();
+        std::iter::once(base).chain(search_path)
+            .map(|dir| dir.join(path))
+            .find(|candidate| candidate.is_file())
+            .and_then(|candidate| candidate.canonicalize().ok())
+            .ok_or_else(|| format!("Could not find module '{}'.", path))
+    }
+
+    // Returns false once there is no importer left to return to.
+    fn finish_module(&mut self) -> Result<bool, String> {
+        if self.importers.is_empty() {
+            return Ok(false);
+        }
+        let mut names: Vec<String> = self.globals.iter()
+            .filter(|(name, global)| !self.builtins.get(*name).is_some_and(|builtin| self.equal(builtin, &global.value)))
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort();
+        for name in names {
+            let value = self.globals[&name].value.clone();
+            self.push_string(&name)?;
+            self.push(value);
+        }
+        let mut namespace = ObjMap::new();
+        for pair in self.stack.chunks(2) {
+            namespace.insert(self.heap.map_key(&pair[0])?, pair[0].clone(), pair[1].clone());
+        }
+        namespace.freeze();
+        let module = Obj(self.allocate(HeapData::Map(namespace))?);
+
+        if let Some(path) = self.script_path.clone() {
+            self.modules.insert(path, module.clone());
+        }
+        let importer = self.importers.pop().unwrap();
+        self.resume_importer(importer);
+        self.push(module);
+        Ok(true)
+    }
+
+    fn resume_importer(&mut self, importer: Importer) {
+        self.chunk = importer.chunk;
+        self.ip = importer.ip;
+        self.stack = importer.stack;
+        self.globals = importer.globals;
+        self.script_path = importer.script_path;
+    }
+
+    fn throw(&mut self, value: Value) -> InterpretResult {
+        let message = match self.error_field(&value, "message") {
+            Some(message @ Obj(_)) => self.format_value(&message, FormatMode::Display),
+            _ => self.format_value(&value, FormatMode::Display),
+        };
+        let result = match self.error_field(&value, "line") {
+            // Rethrown errors keep reporting where they were first raised.
+            Some(Number(line)) => InterpretResult::RuntimeError(RuntimeError {
+                message,
+                trace: vec![TraceFrame { function: self.frame_name(), line: line as usize }],
+            }),
+            _ => self.runtime_error(message),
+        };
+        self.thrown = Some(value);
+        result
+    }
+
+    fn error_field(&self, error: &Value, field: &str) -> Option<Value> {
+        self.as_map(error)?.get(&MapKey::String(field.to_string())).cloned()
+    }
+
+    // Builds the `{"message": ..., "line": ..., "trace": [...]}` map a catch block receives.
+    // Every piece stays on the stack until the map owns it, so a collection can't free it early.
+    // The map is allocated past the memory cap, otherwise "Out of memory." could never be caught.
+    fn error_object(&mut self, error: &RuntimeError) -> Result<Value, String> {
+        let base = self.stack.len();
+        let max_bytes = self.heap.max_bytes.take();
+        let result = self.build_error_object(error);
+        self.heap.max_bytes = max_bytes;
+        self.stack.truncate(base);
+        result
+    }
+
+    fn build_error_object(&mut self, error: &RuntimeError) -> Result<Value, String> {
+        let base = self.stack.len();
+        self.push_string("message")?;
+        self.push_string(&error.message)?;
+        self.push_string("line")?;
+        self.push(Number(error.trace.first().map_or(0, |frame| frame.line) as f64));
+        self.push_string("trace")?;
+        for line in error.backtrace() {
+            self.push_string(&line)?;
+        }
+        let trace = self.stack[base + 5..].to_vec();
+        let trace = self.allocate(HeapData::List(trace))?;
+        self.stack.truncate(base + 5);
+        self.push(Obj(trace));
+
+        let mut map = ObjMap::new();
+        for pair in self.stack[base..].chunks(2) {
+            map.insert(self.heap.map_key(&pair[0])?, pair[0].clone(), pair[1].clone());
+        }
+        self.allocate(HeapData::Map(map)).map(Obj)
+    }
+
+    fn push_string(&mut self, string: &str) -> Result<(), String> {
+        let string = self.allocate(HeapData::String(string.to_string()))?;
+        self.push(Obj(string));
+        Ok(())
+    }
 
     pub fn run(&mut self) -> InterpretResult {
+        let deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+        let mut executed: usize = 0;
         loop {
+            let result = self.execute(deadline, &mut executed);
+            let InterpretResult::RuntimeError(mut error) = result else {
+                return result;
+            };
+            // An error nobody in a module catches surfaces at the import that loaded it.
+            let handler = loop {
+                if let Some(handler) = self.chunk.handler_at(self.ip.saturating_sub(1)) {
+                    break Some(handler.clone());
+                }
+                let Some(importer) = self.importers.pop() else {
+                    break None;
+                };
+                self.resume_importer(importer);
+                error.trace.push(TraceFrame { function: self.frame_name(), line: self.current_line() });
+            };
+            let Some(handler) = handler else {
+                return self.stop(InterpretResult::RuntimeError(error));
+            };
+            let exception = match self.thrown.take() {
+                Some(value) => value,
+                None => match self.error_object(&error) {
+                    Ok(value) => value,
+                    Err(_) => return self.stop(InterpretResult::RuntimeError(error)),
+                },
+            };
+            self.stack.truncate(handler.stack_depth);
+            self.push(exception);
+            self.ip = handler.target;
+        }
+    }
+
+    fn execute(&mut self, deadline: Option<Instant>, executed: &mut usize) -> InterpretResult {
+        loop {
+            while self.ip >= self.chunk.code.len() {
+                match self.finish_module() {
+                    Ok(true) => (),
+                    Ok(false) => return InterpretResult::Ok,
+                    Err(message) => return self.runtime_error(message),
+                }
+            }
+            if self.limits.max_instructions.is_some_and(|max| *executed >= max) {
+                return self.stop(InterpretResult::OutOfFuel);
+            }
+            if executed.is_multiple_of(DEADLINE_CHECK_INTERVAL) {
+                if self.interrupt.swap(false, Ordering::Relaxed) {
+                    return self.stop(InterpretResult::Interrupted);
+                }
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    return self.stop(InterpretResult::Timeout);
+                }
+            }
+            *executed += 1;
+
             self.debug_trace_stack();
             let instruction = self.read_byte();
             match instruction {
@@ -183,6 +722,18 @@ impl VM {
                 Pop => {
                     self.pop();
                 },
+                Dup => {
+                    self.push(self.peek(0));
+                },
+                Dup2 => {
+                    let (a, b) = (self.peek(1), self.peek(0));
+                    self.push(a);
+                    self.push(b);
+                },
+                Bury(depth) => {
+                    let value = self.pop();
+                    self.stack.insert(self.stack.len() - depth, value);
+                },
                 GetLocal(local_idx) => {
                     let value = self.stack[local_idx].clone();
                     self.push(value);
@@ -197,67 +748,62 @@ impl VM {
                         match obj {
                             HeapData::String(string) => {
                                 match self.globals.get(string) {
-                                    Some(value) => {
-                                        self.push(value.clone());
+                                    Some(global) => {
+                                        self.push(global.value.clone());
                                     },
                                     None => {
-                                        self.runtime_error(format!("Undefined variable '{}'", string));
-                                        return InterpretResult::RuntimeError;
+                                        return self.runtime_error(format!("Undefined variable '{}'", string));
                                     }
                                 }
                             }
                             _ => {
-                                self.runtime_error(format!("Expected string as global variable name"));
-                                return InterpretResult::RuntimeError;
+                                return self.runtime_error("Expected string as global variable name".to_string());
                             }
                         }
                     }
                 },
-                DefineGlobal(const_idx) => {
+                DefineGlobal(const_idx) | DefineConstant(const_idx) => {
                     let val = self.pop();
                     if let Obj(heap_id) = self.chunk.constants[const_idx].clone() {
                         let obj = self.heap.get(&heap_id).unwrap();
                         match obj {
                             HeapData::String(string) => {
-                                self.globals.insert(string.clone(), val);
+                                if self.globals.get(string).is_some_and(|global| global.constant) {
+                                    return self.runtime_error(format!("Can't redefine constant '{}'.", string));
+                                }
+                                let constant = matches!(instruction, DefineConstant(_));
+                                self.globals.insert(string.clone(), Global::new(val, constant));
                             },
                             _ => {
-                                self.runtime_error(format!("Expected string as global variable name"));
-                                return InterpretResult::RuntimeError;
+                                return self.runtime_error("Expected string as global variable name".to_string());
                             }
                         }
                     }
-                    if self.ip >= self.chunk.code.len() {
-                        return InterpretResult::Ok;
-                    }
                 },
                 SetGlobal(const_idx) => {
-                    let val = self.pop();
+                    let val = self.peek(0);
                     if let Obj(heap_id) = self.chunk.constants[const_idx].clone() {
                         let obj = self.heap.get(&heap_id).unwrap();
                         match obj {
                             HeapData::String(string) => {
-                                if self.globals.contains_key(string) {
-                                    self.globals.insert(string.clone(), val);
-                                } else {
-                                    self.runtime_error(format!("Undefined variable '{}'", string));
-                                    return InterpretResult::RuntimeError;
+                                match self.globals.get_mut(string) {
+                                    Some(global) if global.constant => {
+                                        return self.runtime_error(format!("Can't assign to constant '{}'.", string));
+                                    },
+                                    Some(global) => global.value = val,
+                                    None => return self.runtime_error(format!("Undefined variable '{}'", string)),
                                 }
                             }
                             _ => {
-                                self.runtime_error(format!("Expected string as global variable name"));
-                                return InterpretResult::RuntimeError;
+                                return self.runtime_error("Expected string as global variable name".to_string());
                             }
                         }
                     }
-                    if self.ip >= self.chunk.code.len() {
-                        return InterpretResult::Ok;
-                    }
                 }
                 Equal => {
                     let b = self.pop();
                     let a = self.pop();
-                    self.push(Bool(self.equal(a, b)));
+                    self.push(Bool(self.equal(&a, &b)));
                 },
                 Greater => {
                     binary_op!(self, Bool, >);
@@ -268,72 +814,16 @@ impl VM {
                 Add => {
                     let b = self.pop();
                     let a = self.pop();
-                    if let Number(a) = a {
-                        if let Number(b) = b {
-                            self.push(Number(a + b));
-                        }
-                        else if let Obj(b) = b {
-                            let obj_b = self.heap.get(&b).unwrap();
-                            match obj_b {
-                                HeapData::String(b_string) => {
-                                    let mut new_string = b_string.clone();
-                                    new_string.insert_str(0, &a.to_string());
-                                    let new_obj = self.heap.allocate(HeapData::String(new_string));
-                                    self.push(Obj(new_obj));
-                                    self.heap.free(b);
-                                },
-                                _ => {
-                                    self.runtime_error(format!("Operands must be two numbers or two strings or one of each"));
-                                    return InterpretResult::RuntimeError;
-                                }
-                            }
-                        }
-                    } else if let Obj(a) = a {
-                        if let Obj(b) = b {
-                            let obj_a = self.heap.get(&a).unwrap();
-                            let obj_b = self.heap.get(&b).unwrap();
-                            match obj_a {
-                                HeapData::String(a_string) => {
-                                    match obj_b {
-                                        HeapData::String(b_string) => {
-                                            let mut new_string = a_string.clone();
-                                            new_string.push_str(&b_string);
-                                            let new_obj = self.heap.allocate(HeapData::String(new_string));
-                                            self.push(Obj(new_obj));
-                                            self.heap.free(a);
-                                            self.heap.free(b);
-                                        },
-                                        _ => {
-                                            self.runtime_error(format!("Operands must be two numbers or two strings or one of each"));
-                                            return InterpretResult::RuntimeError;
-                                        }
-                                    }
-                                },
-                                _ => {
-                                    self.runtime_error(format!("Operands must be two numbers or two strings or one of each"));
-                                    return InterpretResult::RuntimeError;
-                                }
-                            }
-                        } else if let Number(b) = b {
-                            let obj_a = self.heap.get(&a).unwrap();
-                            match obj_a {
-                                HeapData::String(a_string) => {
-                                    let mut new_string = a_string.clone();
-                                    new_string.push_str(&b.to_string());
-                                    let new_obj = self.heap.allocate(HeapData::String(new_string));
-                                    self.push(Obj(new_obj));
-                                    self.heap.free(a);
-                                },
-                                _ => {
-                                    self.runtime_error(format!("Operands must be two numbers or two strings or one of each"));
-                                    return InterpretResult::RuntimeError;
-                                }
-                            }
-
+                    if let (Number(a), Number(b)) = (&a, &b) {
+                        self.push(Number(a + b));
+                    } else if self.is_concatenable(&a) && self.is_concatenable(&b) {
+                        let new_string = self.format_value(&a, FormatMode::Display) + &self.format_value(&b, FormatMode::Display);
+                        match self.allocate(HeapData::String(new_string)) {
+                            Ok(new_obj) => self.push(Obj(new_obj)),
+                            Err(message) => return self.runtime_error(message),
                         }
                     } else {
-                        self.runtime_error(format!("Operands must be two numbers or two strings or one of each"));
-                        return InterpretResult::RuntimeError;
+                        return self.runtime_error("Operands must be two numbers or two strings or one of each".to_string());
                     }
                 },
                 Subtract => {
@@ -345,91 +835,648 @@ impl VM {
                 Divide => {
                     binary_op!(self, Number, /);
                 },
+                Modulo => {
+                    binary_op!(self, fn modulo);
+                },
+                Power => {
+                    binary_op!(self, fn f64::powf);
+                },
+                BitAnd => {
+                    integer_op!(self, |a, b| Ok(a & b));
+                },
+                BitOr => {
+                    integer_op!(self, |a, b| Ok(a | b));
+                },
+                BitXor => {
+                    integer_op!(self, |a, b| Ok(a ^ b));
+                },
+                ShiftLeft => {
+                    integer_op!(self, |a: i64, b| shift_amount(b).map(|b| a.wrapping_shl(b)));
+                },
+                ShiftRight => {
+                    integer_op!(self, |a: i64, b| shift_amount(b).map(|b| a >> b));
+                },
+                BitNot => {
+                    let value = self.pop();
+                    let Some(value) = to_integer(&value) else {
+                        return self.runtime_error("Operand must be an integer".to_string());
+                    };
+                    self.push(Number(!value as f64));
+                },
                 Not => {
                     let val = self.pop();
-                    if let Bool(value) = val {
-                        self.push(Bool(!value));
-                    } else if val == Value::Nil {
-                        self.push(Bool(true));
-                    } else {
-                        self.runtime_error(format!("Operand must be a boolean"));
-                        return InterpretResult::RuntimeError;
-                    }
-                }
+                    self.push(Bool(self.is_falsey(&val)));
+                },
                 Negate => {
                     if let Number(num) = self.pop() {
                         self.push(Number(-num));
                     } else {
-                        self.runtime_error(format!("Operand must be a number"));
-                        return InterpretResult::RuntimeError;
+                        return self.runtime_error("Operand must be a number".to_string());
                     }
                 },
                 Print => {
-                    if unsafe{!DEBUG_TRACE_EXECUTION} {
-                        print_value(&self.pop(), &self.heap);
-                        println!();
-                    }
-                    if self.ip >= self.chunk.code.len() {
-                        return InterpretResult::Ok;
-                    }
+                    let value = self.pop();
+                    let text = format!("{}\n", self.format_value(&value, FormatMode::Display));
+                    self.output.write(&text);
                 },
                 JumpIfFalse(offset) => {
                     let val = self.peek(0);
-                    if val == Value::Nil || (val == Bool(false)) {
+                    if self.is_falsey(&val) {
                         self.ip += offset;
                     }
-                    if self.ip >= self.chunk.code.len() {
-                        return InterpretResult::Ok;
+                },
+                JumpIfNotNil(offset) => {
+                    if !matches!(self.peek(0), Value::Nil) {
+                        self.ip += offset;
                     }
                 },
                 Jump(offset) => {
                     self.ip += offset;
-                    if self.ip >= self.chunk.code.len() {
-                        return InterpretResult::Ok;
-                    }
                 },
                 Loop(offset) => {
                     self.ip -= offset;
-                    if self.ip >= self.chunk.code.len() {
-                        return InterpretResult::Ok;
+                },
+                Call(arg_count) => {
+                    if let Err(message) = self.call_value(arg_count) {
+                        return self.runtime_error(message);
+                    }
+                },
+                BuildList(count) => {
+                    let elements = self.stack[self.stack.len() - count..].to_vec();
+                    match self.allocate(HeapData::List(elements)) {
+                        Ok(list) => {
+                            self.stack.truncate(self.stack.len() - count);
+                            self.push(Obj(list));
+                        },
+                        Err(message) => return self.runtime_error(message),
+                    }
+                },
+                BuildMap(count) => {
+                    let entries = self.stack[self.stack.len() - count * 2..].to_vec();
+                    let mut map = ObjMap::new();
+                    for pair in entries.chunks(2) {
+                        match self.heap.map_key(&pair[0]) {
+                            Ok(key) => map.insert(key, pair[0].clone(), pair[1].clone()),
+                            Err(message) => return self.runtime_error(message),
+                        }
+                    }
+                    match self.allocate(HeapData::Map(map)) {
+                        Ok(map) => {
+                            self.stack.truncate(self.stack.len() - count * 2);
+                            self.push(Obj(map));
+                        },
+                        Err(message) => return self.runtime_error(message),
+                    }
+                },
+                IterInit => {
+                    if !self.is_iterable(&self.peek(0)) {
+                        return self.runtime_error("Can only iterate over lists, maps, strings and ranges.".to_string());
+                    }
+                    self.push(Number(0.0));
+                },
+                IterNext(slot) => {
+                    match self.iter_next(slot) {
+                        Ok(Some(value)) => {
+                            self.push(value);
+                            self.push(Bool(true));
+                        },
+                        Ok(None) => self.push(Bool(false)),
+                        Err(message) => return self.runtime_error(message),
+                    }
+                },
+                IndexGet => {
+                    let index = self.pop();
+                    let target = self.pop();
+                    match self.index_get(&target, &index) {
+                        Ok(value) => self.push(value),
+                        Err(message) => return self.runtime_error(message),
+                    }
+                },
+                IndexSet => {
+                    let value = self.peek(0);
+                    let index = self.peek(1);
+                    let target = self.peek(2);
+                    if let Err(message) = self.index_set(&target, &index, value.clone()) {
+                        return self.runtime_error(message);
+                    }
+                    self.stack.truncate(self.stack.len() - 3);
+                    self.push(value);
+                },
+                Throw => {
+                    let value = self.pop();
+                    return self.throw(value);
+                },
+                EndFinally => {
+                    let pending = self.pop();
+                    let error = self.pop();
+                    match pending {
+                        Bool(true) => return self.throw(error),
+                        Number(target) => self.ip = target as usize,
+                        _ => (),
+                    }
+                },
+                Import(const_idx) => {
+                    let path = self.format_value(&self.chunk.constants[const_idx].clone(), FormatMode::Display);
+                    if let Err(message) = self.import(&path) {
+                        return self.runtime_error(message);
+                    }
+                },
+                ImportName(const_idx) => {
+                    let module = self.pop();
+                    let name = self.format_value(&self.chunk.constants[const_idx].clone(), FormatMode::Display);
+                    let value = self.as_map(&module).and_then(|module| module.get(&MapKey::String(name.clone())));
+                    match value {
+                        Some(value) => self.push(value.clone()),
+                        None => return self.runtime_error(format!("Module has no variable '{}'.", name)),
                     }
                 },
                 Return => {
-                    return InterpretResult::Ok;
+                    match self.finish_module() {
+                        Ok(true) => (),
+                        Ok(false) => return InterpretResult::Ok,
+                        Err(message) => return self.runtime_error(message),
+                    }
                 },
             }
-            if self.ip >= self.chunk.code.len() {
-                return InterpretResult::Ok;
+            if self.stack.len() > self.limits.max_stack {
+                return self.runtime_error("Stack overflow.".to_string());
             }
         }
     }
 
-    fn debug_trace_stack(&self) {
-        unsafe {
-            if !DEBUG_TRACE_EXECUTION {
-                return;
-            }
+    fn debug_trace_stack(&mut self) {
+        if !self.trace.enabled {
+            return;
         }
-        print!("          ");
-        for value in self.stack.iter() {
-            if let Value::Obj(obj) = value {
-                let obj = self.heap.get(obj).unwrap();
-                print!("[ ");
-                print!("Obj({:^10}) ", format!("\"{}\"", obj.as_string()));
-                print!("]");
-            } else {
-                print!("[ ");
-                print!("{:^10?} ", value);
-                print!("]");
-            }
+        let instruction = dissassemble_instruction(&self.chunk, &self.heap, self.ip);
+        if !self.trace.accepts(SCRIPT_NAME, &instruction) {
+            return;
         }
-        print!("\r\n");
-        dissassemble_instruction(&self.chunk, &self.heap, self.ip);
+        let stack: Vec<String> = self.stack.iter()
+            .map(|value| self.format_value(value, FormatMode::Repr))
+            .collect();
+        self.trace.write(&stack, &instruction);
     }
 }
 
+// Past 2^53 an f64 can't tell neighbouring integers apart, so bitwise results would be silently wrong.
+const MAX_SAFE_INTEGER: f64 = 9007199254740992.0;
+
+fn to_integer(value: &Value) -> Option<i64> {
+    match value {
+        Number(num) if num.fract() == 0.0 && num.abs() <= MAX_SAFE_INTEGER => Some(*num as i64),
+        _ => None,
+    }
+}
+
+fn shift_amount(amount: i64) -> Result<u32, String> {
+    if !(0..64).contains(&amount) {
+        return Err("Shift amount must be between 0 and 63".to_string());
+    }
+    Ok(amount as u32)
+}
+
+// The result takes the sign of the divisor, so `-1 % 5` is 4 and counters wrap the way you'd expect.
+pub fn modulo(a: f64, b: f64) -> f64 {
+    let remainder = a % b;
+    if remainder != 0.0 && (remainder < 0.0) != (b < 0.0) {
+        remainder + b
+    } else {
+        remainder
+    }
+}
+
+pub fn list_index(index: &Value, len: usize) -> Result<usize, String> {
+    let Number(index) = index else {
+        return Err("List index must be a number.".to_string());
+    };
+    if index.fract() != 0.0 {
+        return Err("List index must be an integer.".to_string());
+    }
+    let resolved = if *index < 0.0 { *index + len as f64 } else { *index };
+    if resolved < 0.0 || resolved >= len as f64 {
+        return Err("List index out of range.".to_string());
+    }
+    Ok(resolved as usize)
+}
+
 pub enum InterpretResult {
     Ok,
     CompileError,
-    RuntimeError,
-}
\ No newline at end of file
+    RuntimeError(RuntimeError),
+    OutOfFuel,
+    Timeout,
+    Interrupted,
+}
+
+impl std::fmt::Display for InterpretResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InterpretResult::Ok => Ok(()),
+            InterpretResult::CompileError => write!(f, "Compile error."),
+            InterpretResult::RuntimeError(err) => write!(f, "{}", err),
+            InterpretResult::OutOfFuel => write!(f, "Instruction budget exhausted."),
+            InterpretResult::Timeout => write!(f, "Execution timed out."),
+            InterpretResult::Interrupted => write!(f, "Execution interrupted."),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceFrame {
+    pub function: String,
+    pub line: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub message: String,
+    pub trace: Vec<TraceFrame>,
+}
+
+impl RuntimeError {
+    pub fn backtrace(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut idx = 0;
+        while idx < self.trace.len() {
+            let function = &self.trace[idx].function;
+            let run = self.trace[idx..].iter().take_while(|frame| &frame.function == function).count();
+            for frame in self.trace[idx..idx + run.min(COLLAPSE_AFTER)].iter() {
+                lines.push(frame.to_string());
+            }
+            if run > COLLAPSE_AFTER {
+                lines.push(format!("... {} more frames of {}", run - COLLAPSE_AFTER, function));
+            }
+            idx += run;
+        }
+        lines
+    }
+}
+
+impl std::fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.function == SCRIPT_NAME {
+            write!(f, "[line {}] in script", self.line)
+        } else if self.function.contains(MAIN_SEPARATOR) {
+            write!(f, "[line {}] in {}", self.line, self.function)
+        } else {
+            write!(f, "[line {}] in {}()", self.line, self.function)
+        }
+    }
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        for line in self.backtrace() {
+            write!(f, "\n{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::BufferOutput;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn run_capturing(vm: &mut VM, source: &str) -> (InterpretResult, String) {
+        let buffer = BufferOutput::new();
+        vm.output = Box::new(buffer.clone());
+        let result = vm.interpret(source.to_string());
+        (result, buffer.take())
+    }
+
+    fn run(source: &str) -> String {
+        let (result, output) = run_capturing(&mut VM::new(), source);
+        assert!(matches!(result, InterpretResult::Ok), "script failed: {}", result);
+        output
+    }
+
+    fn compile_fails(source: &str) -> bool {
+        matches!(run_capturing(&mut VM::new(), source).0, InterpretResult::CompileError)
+    }
+
+    fn runtime_error(source: &str) -> String {
+        match run_capturing(&mut VM::new(), source).0 {
+            InterpretResult::RuntimeError(err) => err.message,
+            result => panic!("expected a runtime error, got '{}'", result),
+        }
+    }
+
+    // A fresh directory per test, so module tests can run in parallel.
+    fn module_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("loxvm-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn string(vm: &mut VM, text: &str) -> Value {
+        Obj(vm.allocate(HeapData::String(text.to_string())).unwrap())
+    }
+
+    fn global(vm: &VM, name: &str) -> Value {
+        vm.globals[name].value.clone()
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let source = "
+            for (var i = 0; i < 10; i = i + 1) {
+              if (i == 1) continue;
+              var inner = i * 2;
+              if (i == 4) break;
+              print inner;
+            }
+            var n = 0;
+            while (true) { n = n + 1; if (n > 2) break; }
+            print n;";
+        assert_eq!(run(source), "0\n4\n6\n3\n");
+        assert!(compile_fails("break;"));
+        assert!(compile_fails("for (;;) {} continue;"));
+    }
+
+    #[test]
+    fn lists() {
+        let source = "
+            var xs = [1, 2, 3];
+            xs[0] = 10;
+            push(xs, 4);
+            print xs[-1];
+            print pop(xs);
+            insert(xs, 1, 5);
+            print remove(xs, 0);
+            print xs;
+            print slice(xs, 1, 3);
+            print len(xs);";
+        assert_eq!(run(source), "4\n4\n10\n[5, 2, 3]\n[2, 3]\n3\n");
+        assert_eq!(runtime_error("[1][1];"), "List index out of range.");
+    }
+
+    #[test]
+    fn list_growth_respects_the_memory_cap() {
+        let mut vm = VM::new();
+        vm.heap.max_bytes = Some(200_000);
+        let result = run_capturing(&mut vm, "var xs = []; while (true) push(xs, nil);").0;
+        match result {
+            InterpretResult::RuntimeError(err) => assert_eq!(err.message, "Out of memory."),
+            result => panic!("expected an out-of-memory error, got '{}'", result),
+        }
+        assert!(vm.heap.bytes_allocated() <= 200_000);
+    }
+
+    #[test]
+    fn maps() {
+        let source = "
+            var m = {\"a\": 1, \"b\": 2};
+            m[\"c\"] = 3;
+            m[0] = \"zero\";
+            print m[-0];
+            print has(m, \"a\");
+            delete(m, \"a\");
+            print has(m, \"a\");
+            print keys(m);
+            print values(m);
+            print len(m);";
+        assert_eq!(run(source), "zero\ntrue\nfalse\n[\"b\", \"c\", 0]\n[2, 3, \"zero\"]\n3\n");
+    }
+
+    #[test]
+    fn map_growth_respects_the_memory_cap() {
+        let mut vm = VM::new();
+        vm.heap.max_bytes = Some(200_000);
+        let result = run_capturing(&mut vm, "var m = {}; var i = 0; while (true) { m[i] = nil; i = i + 1; }").0;
+        match result {
+            InterpretResult::RuntimeError(err) => assert_eq!(err.message, "Out of memory."),
+            result => panic!("expected an out-of-memory error, got '{}'", result),
+        }
+        assert!(vm.heap.bytes_allocated() <= 200_000);
+    }
+
+    #[test]
+    fn for_in_loops() {
+        let source = "
+            var out = \"\";
+            for (var x in [1, 2]) out = out + x;
+            for (var k in {\"a\": 1}) out = out + k;
+            for (var c in \"hi\") out = out + c;
+            for (var i in range(3)) out = out + i;
+            print out;";
+        assert_eq!(run(source), "12ahi012\n");
+    }
+
+    #[test]
+    fn arithmetic_and_bitwise_operators() {
+        let source = "
+            print 7 % 3;
+            print -7 % 3;
+            print 2 ** 10;
+            print 6 & 3;
+            print 6 | 3;
+            print 6 ^ 3;
+            print ~0;
+            print 1 << 4;
+            print 256 >> 2;
+            print idiv(7, 2);
+            print idiv(-7, 2);
+            print idiv(-7, 2) * 2 + -7 % 2;";
+        assert_eq!(run(source), "1\n2\n1024\n2\n7\n5\n-1\n16\n64\n3\n-4\n-7\n");
+        assert_eq!(runtime_error("1.5 & 1;"), "Operands must be integers");
+    }
+
+    #[test]
+    fn compound_assignment_and_increments() {
+        let source = "
+            var x = 5;
+            x += 2; x -= 1; x *= 3; x /= 2; x %= 5;
+            print x;
+            var xs = [1, 2];
+            var i = 0;
+            xs[i++] += 10;
+            print xs;
+            print i;
+            print ++i;
+            print i--;
+            print i;";
+        assert_eq!(run(source), "4\n[11, 2]\n1\n2\n2\n1\n");
+    }
+
+    #[test]
+    fn ternary_and_nil_coalescing() {
+        let source = "
+            print true ? \"a\" : \"b\";
+            print false ? \"a\" : false ? \"b\" : \"c\";
+            print nil ?? \"default\";
+            print 0 ?? \"default\";
+            var hit = false;
+            print 1 ?? (hit = true);
+            print hit;";
+        assert_eq!(run(source), "a\nc\ndefault\n0\n1\nfalse\n");
+    }
+
+    #[test]
+    fn try_catch_finally() {
+        let source = "
+            try { print 1 + nil; } catch (e) { print e[\"line\"]; }
+            try { throw {\"code\": 7}; } catch (e) { print e[\"code\"]; } finally { print \"finally\"; }
+            for (var i = 0; i < 3; i++) {
+              try { if (i == 1) continue; if (i == 2) break; print i; } finally { print \"f\" + i; }
+            }
+            try { try { throw \"x\"; } finally { print \"inner\"; } } catch (e) { print e; }";
+        assert_eq!(run(source), "2\n7\nfinally\n0\nf0\nf1\nf2\ninner\nx\n");
+        assert_eq!(runtime_error("throw \"up\";"), "up");
+    }
+
+    #[test]
+    fn out_of_memory_is_catchable() {
+        let mut vm = VM::new();
+        vm.heap.max_bytes = Some(200_000);
+        let source = "
+            var xs = [];
+            try { for (var i = 0; ; i++) push(xs, \"s\" + i); } catch (e) { print e[\"message\"]; }";
+        let (result, output) = run_capturing(&mut vm, source);
+        assert!(matches!(result, InterpretResult::Ok), "script failed: {}", result);
+        assert_eq!(output, "Out of memory.\n");
+    }
+
+    #[test]
+    fn modules_are_cached_and_namespaced() {
+        let dir = module_dir("modules");
+        fs::write(dir.join("util.lox"), "var greeting = \"hi\"; print \"loading\";").unwrap();
+        let source = "
+            import \"util.lox\" as util;
+            from \"util.lox\" import greeting;
+            print util[\"greeting\"];
+            print greeting;";
+        let mut vm = VM::new();
+        vm.script_path = Some(dir.join("main.lox"));
+        let (result, output) = run_capturing(&mut vm, source);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(result, InterpretResult::Ok), "script failed: {}", result);
+        assert_eq!(output, "loading\nhi\nhi\n");
+    }
+
+    #[test]
+    fn import_cycles_are_reported() {
+        let dir = module_dir("cycle");
+        fs::write(dir.join("a.lox"), "import \"b.lox\" as b;").unwrap();
+        fs::write(dir.join("b.lox"), "import \"a.lox\" as a;").unwrap();
+        let mut vm = VM::new();
+        vm.script_path = Some(dir.join("main.lox"));
+        let result = run_capturing(&mut vm, "import \"a.lox\" as a;").0;
+        fs::remove_dir_all(&dir).unwrap();
+        match result {
+            InterpretResult::RuntimeError(err) => assert!(err.message.starts_with("Import cycle"), "{}", err.message),
+            result => panic!("expected an import cycle, got '{}'", result),
+        }
+    }
+
+    #[test]
+    fn imports_are_read_only() {
+        let dir = module_dir("read-only");
+        fs::write(dir.join("util.lox"), "const c = 2; var v = 1;").unwrap();
+        let mut errors = Vec::new();
+        for source in ["import \"util.lox\" as util; util[\"c\"] = 99;", "import \"util.lox\" as util; delete(util, \"v\");"] {
+            let mut vm = VM::new();
+            vm.script_path = Some(dir.join("main.lox"));
+            match run_capturing(&mut vm, source).0 {
+                InterpretResult::RuntimeError(err) => errors.push(err.message),
+                result => panic!("expected a runtime error, got '{}'", result),
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(errors, ["Module namespaces are read-only.", "Module namespaces are read-only."]);
+        assert!(compile_fails("from \"util.lox\" import c; c = 5;"));
+        assert!(compile_fails("{ from \"util.lox\" import v; v = 5; }"));
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(run("const limit = 3; { const inner = 1; print inner + limit; }"), "4\n");
+        assert!(compile_fails("const a = 1; a = 2;"));
+        assert!(compile_fails("{ const b = 1; b = 2; }"));
+        assert!(compile_fails("const c;"));
+        assert_eq!(runtime_error("const d = 1; var d = 2;"), "Can't redefine constant 'd'.");
+    }
+
+    #[test]
+    fn vm_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<VM>();
+    }
+
+    #[test]
+    fn print_goes_to_output() {
+        assert_eq!(run("print 1; print \"two\";"), "1\ntwo\n");
+    }
+
+    #[test]
+    fn buffer_keeps_output_until_taken() {
+        let mut vm = VM::new();
+        let buffer = BufferOutput::new();
+        vm.output = Box::new(buffer.clone());
+        vm.interpret("print 1;".to_string());
+        assert_eq!(buffer.contents(), "1\n");
+        assert_eq!(buffer.take(), "1\n");
+        assert_eq!(buffer.contents(), "");
+    }
+
+    #[test]
+    fn nan_is_not_equal_to_itself() {
+        let vm = VM::new();
+        assert!(!vm.equal(&Number(f64::NAN), &Number(f64::NAN)));
+    }
+
+    #[test]
+    fn negative_zero_equals_zero() {
+        let vm = VM::new();
+        assert!(vm.equal(&Number(-0.0), &Number(0.0)));
+        assert_eq!(run("print -0 == 0;"), "true\n");
+    }
+
+    #[test]
+    fn strings_compare_by_content() {
+        let mut vm = VM::new();
+        let a = string(&mut vm, "lox");
+        let b = string(&mut vm, "lox");
+        let c = string(&mut vm, "vm");
+        assert!(matches!((&a, &b), (Obj(a), Obj(b)) if a != b));
+        assert!(vm.equal(&a, &b));
+        assert!(!vm.equal(&a, &c));
+        assert_eq!(run("var a = \"lo\"; print a + \"x\" == \"l\" + \"ox\";"), "true\n");
+    }
+
+    #[test]
+    fn natives_compare_by_identity() {
+        let vm = VM::new();
+        assert!(vm.equal(&global(&vm, "len"), &global(&vm, "len")));
+        assert!(!vm.equal(&global(&vm, "len"), &global(&vm, "push")));
+    }
+
+    #[test]
+    fn lists_and_maps_compare_by_identity() {
+        let mut vm = VM::new();
+        let source = "var a = [1]; var b = [1]; var c = a; var m = {}; var n = {};";
+        assert!(matches!(run_capturing(&mut vm, source).0, InterpretResult::Ok));
+        assert!(vm.equal(&global(&vm, "a"), &global(&vm, "c")));
+        assert!(!vm.equal(&global(&vm, "a"), &global(&vm, "b")));
+        assert!(vm.equal(&global(&vm, "m"), &global(&vm, "m")));
+        assert!(!vm.equal(&global(&vm, "m"), &global(&vm, "n")));
+        assert!(!vm.equal(&global(&vm, "a"), &global(&vm, "m")));
+    }
+
+    #[test]
+    fn falsey_values_of_different_types_are_not_equal() {
+        let mut vm = VM::new();
+        let empty = string(&mut vm, "");
+        let values = [Value::Nil, Bool(false), Number(0.0), empty];
+        for (i, a) in values.iter().enumerate() {
+            for (j, b) in values.iter().enumerate() {
+                assert_eq!(vm.equal(a, b), i == j, "{:?} == {:?}", a, b);
+            }
+        }
+    }
+}